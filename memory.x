@@ -0,0 +1,10 @@
+MEMORY
+{
+  /* STM32F401CC has 256K. Flash is erased a whole sector at a time, the
+     node config (at most 512 bytes, see STORED_SIZE in src/config.rs)
+     gets the last sector. That sector is 128K, the small 16K sectors are
+     at the start and taking one would split the firmware in two. Linking
+     fails once the firmware outgrows what is left. */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 128K
+  RAM : ORIGIN = 0x20000000, LENGTH = 64K
+}
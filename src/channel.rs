@@ -1,20 +1,41 @@
-use defmt::unwrap;
+use core::cell::Cell;
+
+use defmt::{unwrap, warn};
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::channel;
 use embassy_sync::mutex::Mutex;
 use embassy_sync::priority_channel::{self, PriorityChannel};
+use embassy_sync::signal::Signal;
 use embassy_time::{Duration, Instant};
 use heapless::Vec;
-use protocol::large_bedroom::{Error, LargeBedroom};
+use protocol::large_bedroom::{Device, Error, LargeBedroom, SensorError};
 use protocol::Sensor;
 
+use crate::clock::Clock;
+use crate::latest::LatestValues;
+use crate::sensors::buttons::ButtonEvent;
+use crate::status::Status;
+
+const CRITICAL: u8 = 10;
+const QUEUE_SIZE: usize = 40;
+
 struct ErrorEvent {
     error: Error,
     at: Instant,
 }
 
 pub struct Channel {
-    queue: PriorityChannel<NoopRawMutex, PriorityValue, priority_channel::Max, 40>,
+    queue: PriorityChannel<NoopRawMutex, PriorityValue, priority_channel::Max, QUEUE_SIZE>,
     recent_errors: Mutex<NoopRawMutex, Vec<ErrorEvent, 20>>,
+    status: channel::Channel<NoopRawMutex, Status, 8>,
+    /// Send before anything else
+    buttons: channel::Channel<NoopRawMutex, ButtonEvent, 8>,
+    button_queued: Signal<NoopRawMutex, ()>,
+    /// Values that are outdated quickly, send as datagrams if enabled
+    stream: channel::Channel<NoopRawMutex, (LargeBedroom, Instant), 8>,
+    stream_enabled: Cell<bool>,
+    latest: LatestValues,
+    clock: Clock,
 }
 
 impl Channel {
@@ -22,11 +43,27 @@ impl Channel {
         Self {
             queue: PriorityChannel::new(),
             recent_errors: Mutex::new(Vec::new()),
+            status: channel::Channel::new(),
+            buttons: channel::Channel::new(),
+            button_queued: Signal::new(),
+            stream: channel::Channel::new(),
+            stream_enabled: Cell::new(false),
+            latest: LatestValues::new(),
+            clock: Clock::new(),
         }
     }
 
+    /// Drops queued values except those that must arrive
     pub async fn clear(&self) {
-        while let Ok(_) = self.queue.try_receive() {}
+        let mut keep: Vec<PriorityValue, QUEUE_SIZE> = Vec::new();
+        while let Ok(value) = self.queue.try_receive() {
+            if value.must_arrive() {
+                let _cant_be_full = keep.push(value);
+            }
+        }
+        for value in keep {
+            let _cant_be_full = self.queue.try_send(value);
+        }
         self.recent_errors.lock().await.clear();
     }
 
@@ -38,6 +75,49 @@ impl Channel {
         self.queue.try_receive().ok()
     }
 
+    pub fn next_status(&self) -> Option<Status> {
+        self.status.try_receive().ok()
+    }
+
+    /// Drops the oldest status if the queue is full, they wait for a
+    /// collector that asks for sequenced frames
+    pub fn send_status(&self, status: Status) {
+        if let Err(channel::TrySendError::Full(status)) = self.status.try_send(status) {
+            let _ = self.status.try_receive();
+            let _ignore_full = self.status.try_send(status);
+        }
+    }
+
+    pub fn send_button(&self, event: ButtonEvent) {
+        if let Err(channel::TrySendError::Full(event)) = self.buttons.try_send(event) {
+            warn!("button queue full, dropping: {}", event);
+        }
+        self.button_queued.signal(());
+    }
+
+    pub fn next_button(&self) -> Option<ButtonEvent> {
+        self.buttons.try_receive().ok()
+    }
+
+    /// Returns once a button event was queued, can return spuriously
+    pub async fn button_ready(&self) {
+        self.button_queued.wait().await
+    }
+
+    /// From now on values send with `send_stream_*` bypass the
+    /// priority queue, take them using [`Channel::receive_stream`]
+    pub fn enable_stream(&self) {
+        self.stream_enabled.set(true);
+    }
+
+    pub async fn receive_stream(&self) -> (LargeBedroom, Instant) {
+        self.stream.receive().await
+    }
+
+    pub fn next_stream_ready(&self) -> Option<(LargeBedroom, Instant)> {
+        self.stream.try_receive().ok()
+    }
+
     pub fn send_error(&self, error: Error) {
         let mut recent_errors = unwrap!(self.recent_errors.try_lock());
 
@@ -54,6 +134,7 @@ impl Channel {
             recent_errors.swap_remove(*idx);
         }
         let entry = PriorityValue {
+            at: Instant::now(),
             priority: 0,
             value: Sensor::LargeBedroomError(error.clone()),
         };
@@ -70,6 +151,7 @@ impl Channel {
 
     pub fn send_p0(&self, value: LargeBedroom) {
         let entry = PriorityValue {
+            at: Instant::now(),
             priority: 0,
             value: Sensor::LargeBedroom(value),
         };
@@ -77,6 +159,7 @@ impl Channel {
     }
     pub fn send_p1(&self, value: LargeBedroom) {
         let entry = PriorityValue {
+            at: Instant::now(),
             priority: 1,
             value: Sensor::LargeBedroom(value),
         };
@@ -85,25 +168,75 @@ impl Channel {
 
     pub fn send_p2(&self, value: LargeBedroom) {
         let entry = PriorityValue {
+            at: Instant::now(),
             priority: 2,
             value: Sensor::LargeBedroom(value),
         };
         let _ignore_full = self.queue.try_send(entry);
     }
 
+    pub fn send_stream_p1(&self, value: LargeBedroom) {
+        if self.stream_enabled.get() {
+            let _ignore_full = self.stream.try_send((value, Instant::now()));
+        } else {
+            self.send_p1(value)
+        }
+    }
+
+    pub fn send_stream_p2(&self, value: LargeBedroom) {
+        if self.stream_enabled.get() {
+            let _ignore_full = self.stream.try_send((value, Instant::now()));
+        } else {
+            self.send_p2(value)
+        }
+    }
+
     pub async fn send_critical_error(&self, error: Error) {
         let entry = PriorityValue {
-            priority: 10,
+            at: Instant::now(),
+            priority: CRITICAL,
             value: Sensor::LargeBedroomError(error),
         };
 
         self.queue.send(entry).await;
     }
+
+    /// Most recent value of every quantity measured so far, the
+    /// measure loops keep it up to date
+    pub fn latest(&self) -> &LatestValues {
+        &self.latest
+    }
+
+    /// Turns the sample instants of values into wall clock time
+    pub fn clock(&self) -> &Clock {
+        &self.clock
+    }
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn device_of(error: &Error) -> Device {
+    match error {
+        Error::Running(err) | Error::Setup(err) => match err {
+            SensorError::Bme680(_) => Device::Bme680,
+            SensorError::Max44(_) => Device::Max44,
+            SensorError::Sht31(_) => Device::Sht31,
+            SensorError::Sps30(_) => Device::Sps30,
+            SensorError::Mhz14(_) => Device::Mhz14,
+        },
+        Error::Timeout(device) | Error::SetupTimedOut(device) => device.clone(),
+    }
 }
 
 /// Higher prio will be send earlier
 pub struct PriorityValue {
     priority: u8,
+    /// When the value was sampled
+    pub at: Instant,
     pub value: Sensor,
 }
 
@@ -111,6 +244,13 @@ impl PriorityValue {
     pub fn low_priority(&self) -> bool {
         self.priority < 2
     }
+
+    /// Button presses and critical errors are send again if the
+    /// connection drops before the collector acknowledges them
+    pub fn must_arrive(&self) -> bool {
+        matches!(self.value, Sensor::LargeBedroom(LargeBedroom::BedButton(_)))
+            || self.priority == CRITICAL
+    }
 }
 
 impl Eq for PriorityValue {}
@@ -131,3 +271,88 @@ impl Ord for PriorityValue {
         self.priority.cmp(&other.priority)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_error_is_send_once() {
+        let publish = Channel::new();
+        let error = Error::Timeout(Device::Sht31);
+
+        publish.send_error(error.clone());
+        publish.send_error(error);
+
+        let sent = publish.next_ready().map(|sent| sent.value);
+        assert!(matches!(
+            sent,
+            Some(Sensor::LargeBedroomError(Error::Timeout(Device::Sht31)))
+        ));
+        assert!(publish.next_ready().is_none());
+    }
+
+    #[test]
+    fn errors_of_different_sensors_are_all_send() {
+        let publish = Channel::new();
+
+        publish.send_error(Error::Timeout(Device::Sht31));
+        publish.send_error(Error::Timeout(Device::Mhz14));
+
+        assert!(publish.next_ready().is_some());
+        assert!(publish.next_ready().is_some());
+        assert!(publish.next_ready().is_none());
+    }
+
+    #[test]
+    fn button_presses_must_arrive() {
+        let publish = Channel::new();
+        publish.send_p0(LargeBedroom::Temperature(20.0));
+        publish.send_p2(LargeBedroom::BedButton(
+            protocol::large_bedroom::BedButton::TopLeft(protocol::Press(100)),
+        ));
+
+        let first = publish.next_ready().map(|next| next.must_arrive());
+        let second = publish.next_ready().map(|next| next.must_arrive());
+        assert_eq!((first, second), (Some(true), Some(false)));
+    }
+
+    #[test]
+    fn clearing_keeps_what_must_arrive() {
+        let publish = Channel::new();
+        publish.send_p0(LargeBedroom::Temperature(20.0));
+        publish.send_p2(LargeBedroom::BedButton(
+            protocol::large_bedroom::BedButton::TopLeft(protocol::Press(100)),
+        ));
+        embassy_futures::block_on(async {
+            publish
+                .send_critical_error(Error::Timeout(Device::Sht31))
+                .await;
+            publish.send_error(Error::Timeout(Device::Mhz14));
+            publish.clear().await;
+        });
+
+        let first = publish.next_ready().map(|next| next.must_arrive());
+        let second = publish.next_ready().map(|next| next.must_arrive());
+        assert_eq!((first, second), (Some(true), Some(true)));
+        assert!(publish.next_ready().is_none());
+    }
+
+    #[test]
+    fn newest_statuses_are_kept() {
+        let publish = Channel::new();
+        for attempts in 0..10 {
+            publish.send_status(Status::Reconnected {
+                attempts,
+                disconnected_secs: 0,
+                lost_messages: 0,
+            });
+        }
+
+        let oldest = publish.next_status();
+        assert!(matches!(
+            oldest,
+            Some(Status::Reconnected { attempts: 2, .. })
+        ));
+    }
+}
@@ -0,0 +1,57 @@
+use core::cell::Cell;
+
+use defmt::info;
+use embassy_time::Instant;
+use serde::{Deserialize, Serialize};
+
+/// When a reading was taken
+#[derive(Clone, Copy, defmt::Format, Serialize, Deserialize)]
+pub enum SampleTime {
+    /// Milliseconds since the unix epoch
+    Unix(u64),
+    /// Milliseconds since boot, the clock has not been set yet
+    Uptime(u64),
+}
+
+impl SampleTime {
+    /// Largest encoded size, a u64 varint and the variant
+    pub const MAX_SIZE: usize = 11;
+}
+
+/// Wall clock time, set through sntp or by the collector
+pub struct Clock {
+    /// Unix time in milliseconds at the moment the node booted
+    boot_unix_ms: Cell<Option<u64>>,
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Self {
+            boot_unix_ms: Cell::new(None),
+        }
+    }
+
+    /// `unix_ms` is the current time in milliseconds since the unix epoch
+    pub fn set(&self, unix_ms: u64) {
+        let boot = unix_ms.saturating_sub(Instant::now().as_millis());
+        if let Some(prev) = self.boot_unix_ms.replace(Some(boot)) {
+            let drift = boot as i64 - prev as i64;
+            info!("clock corrected by {} ms", drift);
+        } else {
+            info!("clock set, booted at unix time {} ms", boot);
+        }
+    }
+
+    pub fn sample_time(&self, at: Instant) -> SampleTime {
+        match self.boot_unix_ms.get() {
+            Some(boot) => SampleTime::Unix(boot + at.as_millis()),
+            None => SampleTime::Uptime(at.as_millis()),
+        }
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,253 @@
+//! Commands send by the collector over the same connection as the
+//! readings. The network task decodes them and hands them to the
+//! sensor loops through [`Commands`], the outcome goes back to the
+//! collector as an [`Ack`].
+
+use core::cell::Cell;
+
+use defmt::{info, unwrap};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::channel;
+use embassy_sync::signal::Signal;
+use embassy_time::Duration;
+use protocol::large_bedroom::Device;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{LuxFilter, Sampling, Schedule};
+use crate::sensors::health::{Health, Registry};
+
+/// Time the ack for a reboot gets to reach the collector
+#[cfg(feature = "board")]
+const REBOOT_DELAY: Duration = Duration::from_millis(500);
+const MIN_INTERVAL: Duration = Duration::from_millis(50);
+const MAX_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Serialize, Deserialize, defmt::Format)]
+pub enum Command {
+    /// Measure the slow sensors now instead of at the end of the interval
+    MeasureNow,
+    SetInterval {
+        of: Loop,
+        millis: u32,
+    },
+    /// Drop the driver and initialize the sensor again
+    Reinit(Device),
+    /// Blow the dust out of the sps30, takes about 10 seconds during
+    /// which its readings are off
+    CleanFan,
+    Reboot,
+    /// Send the latest value of every quantity in one frame, useful
+    /// right after (re)connecting
+    Snapshot,
+    /// See [`Schedule::together`], not supported for the lux loop
+    SetTogether {
+        of: Loop,
+        together: bool,
+    },
+}
+
+#[derive(Clone, Serialize, Deserialize, defmt::Format)]
+pub enum Loop {
+    /// Temperature, humidity, pressure, co2 and particulate matter
+    Slow,
+    Lux,
+    /// One of the slow sensors
+    Sensor(Device),
+}
+
+#[derive(Clone, defmt::Format, Serialize, Deserialize)]
+pub enum Outcome {
+    Accepted,
+    /// The loop or sensor has no schedule this applies to
+    Unsupported,
+    IntervalOutOfRange,
+    /// The sensor has no working driver right now, try again once it
+    /// reports healthy
+    NotReady,
+}
+
+/// Answer to the command with the same id
+#[derive(Clone, defmt::Format, Serialize, Deserialize)]
+pub struct Ack {
+    pub id: u32,
+    pub outcome: Outcome,
+}
+
+/// Schedule of one of the slow sensors
+pub struct Scheduled {
+    schedule: Cell<Schedule>,
+    /// Cuts the wait for the next turn short
+    pub measure_now: Signal<NoopRawMutex, ()>,
+}
+
+impl Scheduled {
+    fn new(schedule: Schedule) -> Self {
+        Self {
+            schedule: Cell::new(schedule),
+            measure_now: Signal::new(),
+        }
+    }
+
+    pub fn get(&self) -> Schedule {
+        self.schedule.get()
+    }
+}
+
+/// Shared between the network task and the sensor loops
+pub struct Commands<'a> {
+    pub reinit: channel::Channel<NoopRawMutex, Device, 5>,
+    pub clean_fan: Signal<NoopRawMutex, ()>,
+    sht31: Scheduled,
+    bme680: Scheduled,
+    mhz14: Scheduled,
+    sps30: Scheduled,
+    lux_interval: Cell<Duration>,
+    lux_filter: LuxFilter,
+    reboot: Signal<NoopRawMutex, ()>,
+    snapshot: Signal<NoopRawMutex, ()>,
+    acks: channel::Channel<NoopRawMutex, Ack, 4>,
+    ack_queued: Signal<NoopRawMutex, ()>,
+    /// Which sensors currently have a driver
+    health: &'a Registry,
+}
+
+impl<'a> Commands<'a> {
+    pub fn new(sampling: &Sampling, health: &'a Registry) -> Self {
+        Self {
+            reinit: channel::Channel::new(),
+            clean_fan: Signal::new(),
+            sht31: Scheduled::new(sampling.sht31),
+            bme680: Scheduled::new(sampling.bme680),
+            mhz14: Scheduled::new(sampling.mhz14),
+            sps30: Scheduled::new(sampling.sps30),
+            lux_interval: Cell::new(Duration::from_millis(sampling.lux_interval_ms as u64)),
+            lux_filter: sampling.lux_filter,
+            reboot: Signal::new(),
+            snapshot: Signal::new(),
+            acks: channel::Channel::new(),
+            ack_queued: Signal::new(),
+            health,
+        }
+    }
+
+    pub fn handle(&self, id: u32, command: Command) {
+        info!("command from collector: {}", command);
+        let outcome = match command {
+            Command::MeasureNow => {
+                for slow in [&self.sht31, &self.bme680, &self.mhz14, &self.sps30] {
+                    slow.measure_now.signal(());
+                }
+                Outcome::Accepted
+            }
+            Command::SetInterval { of, millis } => {
+                let interval = Duration::from_millis(millis as u64);
+                if !(MIN_INTERVAL..=MAX_INTERVAL).contains(&interval) {
+                    Outcome::IntervalOutOfRange
+                } else if let Loop::Lux = of {
+                    self.lux_interval.set(interval);
+                    Outcome::Accepted
+                } else {
+                    self.update_schedules(&of, |schedule| Schedule {
+                        interval_ms: millis,
+                        ..schedule
+                    })
+                }
+            }
+            Command::SetTogether { of: Loop::Lux, .. } => Outcome::Unsupported,
+            Command::SetTogether { of, together } => {
+                self.update_schedules(&of, |schedule| Schedule {
+                    together,
+                    ..schedule
+                })
+            }
+            Command::Reinit(device) => {
+                let _ignore_full = self.reinit.try_send(device);
+                Outcome::Accepted
+            }
+            Command::CleanFan => match self.health.get(&Device::Sps30) {
+                Some(Health::Healthy | Health::Degraded) => {
+                    self.clean_fan.signal(());
+                    Outcome::Accepted
+                }
+                _ => Outcome::NotReady,
+            },
+            Command::Reboot => {
+                self.reboot.signal(());
+                Outcome::Accepted
+            }
+            Command::Snapshot => {
+                self.snapshot.signal(());
+                Outcome::Accepted
+            }
+        };
+        let _ignore_full = self.acks.try_send(Ack { id, outcome });
+        self.ack_queued.signal(());
+    }
+
+    /// Applies `change` to the schedules of the slow sensors `of` refers
+    /// to. These sensors are measured right away so the new schedule
+    /// takes effect without waiting out the old interval.
+    fn update_schedules(&self, of: &Loop, change: impl Fn(Schedule) -> Schedule) -> Outcome {
+        let slow = [Device::Sht31, Device::Bme680, Device::Mhz14, Device::Sps30];
+        let devices = match of {
+            Loop::Slow => &slow[..],
+            Loop::Sensor(device) => core::slice::from_ref(device),
+            Loop::Lux => return Outcome::Unsupported,
+        };
+        for device in devices {
+            let Some(slow) = self.schedule_of(device) else {
+                return Outcome::Unsupported;
+            };
+            slow.schedule.set(change(slow.get()));
+            slow.measure_now.signal(());
+        }
+        Outcome::Accepted
+    }
+
+    fn schedule_of(&self, device: &Device) -> Option<&Scheduled> {
+        match device {
+            Device::Sht31 => Some(&self.sht31),
+            Device::Bme680 => Some(&self.bme680),
+            Device::Mhz14 => Some(&self.mhz14),
+            Device::Sps30 => Some(&self.sps30),
+            _ => None,
+        }
+    }
+
+    /// Schedule of one of the slow sensors
+    pub fn schedule(&self, device: &Device) -> &Scheduled {
+        unwrap!(self.schedule_of(device))
+    }
+
+    pub fn lux_interval(&self) -> Duration {
+        self.lux_interval.get()
+    }
+
+    pub fn lux_filter(&self) -> LuxFilter {
+        self.lux_filter
+    }
+
+    pub fn snapshot_requested(&self) -> bool {
+        self.snapshot.try_take().is_some()
+    }
+
+    pub fn next_ack(&self) -> Option<Ack> {
+        self.acks.try_receive().ok()
+    }
+
+    /// Returns once an ack was queued, can return spuriously
+    pub async fn ack_ready(&self) {
+        self.ack_queued.wait().await
+    }
+
+    /// Resets the node once the collector asks for it
+    #[cfg(feature = "board")]
+    pub async fn reboot_when_asked(&self) {
+        use embassy_time::Timer;
+
+        self.reboot.wait().await;
+        info!("rebooting on request of the collector");
+        Timer::after(REBOOT_DELAY).await;
+        cortex_m::peripheral::SCB::sys_reset();
+    }
+}
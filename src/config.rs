@@ -0,0 +1,511 @@
+use defmt::unwrap;
+use embassy_net::{Ipv4Address, Ipv4Cidr};
+use embassy_time::Duration;
+use heapless::{String, Vec};
+use serde::{Deserialize, Serialize};
+
+/// Start of the last flash sector (128K) relative to the start of flash,
+/// `memory.x` keeps the firmware out of it.
+#[cfg(feature = "board")]
+const CONFIG_OFFSET: u32 = 0x20000;
+/// Marks the sector as containing a config, an erased sector reads as 0xFF
+const MAGIC: [u8; 4] = *b"NCF1";
+/// Follows the magic as a little endian u16. Postcard is not self
+/// describing, a config stored for an older layout could decode into
+/// wrong values. Bump on every change to [`NodeConfig`] or the types
+/// it contains.
+const LAYOUT_VERSION: u16 = 2;
+const STORED_SIZE: usize = 512;
+pub const MAX_HOSTNAME: usize = 32;
+pub const MAX_USERNAME: usize = 32;
+pub const MAX_PASSWORD: usize = 64;
+
+#[derive(Clone, Serialize, Deserialize, defmt::Format)]
+pub struct Collector {
+    /// Hostname, resolved through the dns servers, or an ipv4 address
+    pub host: String<64>,
+    pub port: u16,
+    /// If set, brightness is send as udp datagrams to this port instead
+    /// of over the tcp connection
+    pub udp_port: Option<u16>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Credentials {
+    pub username: String<MAX_USERNAME>,
+    pub password: String<MAX_PASSWORD>,
+}
+
+/// The config is logged on boot, keep the password out of the logs
+impl defmt::Format for Credentials {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "Credentials {{ username: {}, password: <redacted> }}",
+            self.username
+        )
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, defmt::Format)]
+pub struct Broker {
+    /// Hostname, resolved through the dns servers, or an ipv4 address
+    pub host: String<64>,
+    pub port: u16,
+    /// Readings go to `<topic_prefix>/<quantity>`
+    pub topic_prefix: String<32>,
+    pub credentials: Option<Credentials>,
+    /// Announce our quantities to home assistant under this prefix,
+    /// usually `homeassistant`. Not announced if None.
+    pub discovery_prefix: Option<String<32>>,
+}
+
+#[derive(Clone, Serialize, Deserialize, defmt::Format)]
+pub enum Transport {
+    /// Our own framing to the collector, see `network::send_published`
+    Collector,
+    /// Every quantity to its own topic, see `network::mqtt`
+    Mqtt(Broker),
+}
+
+/// When one of the slow sensors is measured
+#[derive(Clone, Copy, Serialize, Deserialize, defmt::Format)]
+pub struct Schedule {
+    pub interval_ms: u32,
+    /// Measure on multiples of the interval since boot, sensors with
+    /// the same (or a multiple of the) interval are then measured in the
+    /// same round. Otherwise the interval starts once the previous
+    /// measurement is done.
+    pub together: bool,
+}
+
+impl Schedule {
+    pub const fn every(interval_ms: u32) -> Self {
+        Self {
+            interval_ms,
+            together: true,
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms as u64)
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, defmt::Format)]
+pub enum Smoothing {
+    None,
+    /// Mean of the last n readings, at most 16
+    MovingAverage(u8),
+    /// Median of the last n readings, at most 16. Ignores single
+    /// outliers such as a reading taken while a PWM dimmed led was off.
+    Median(u8),
+}
+
+/// When a change in brightness is worth reporting
+#[derive(Clone, Copy, Serialize, Deserialize, defmt::Format)]
+pub struct LuxFilter {
+    pub smoothing: Smoothing,
+    /// Report once the brightness changed by this factor compared to
+    /// the last reported value, for example 1.1 for 10%. We perceive
+    /// brightness logarithmically so the factor works at any level.
+    pub min_ratio: f32,
+    /// Below this everything looks equally dark, changes between values
+    /// under the floor are not reported
+    pub floor_lux: f32,
+}
+
+impl LuxFilter {
+    /// Leaves the max44009 interrupt window some room
+    const MIN_RATIO: f32 = 1.01;
+    /// Resolution of the max44009
+    const MIN_FLOOR_LUX: f32 = 0.045;
+
+    /// False if every sample would count as a change, or if the
+    /// interrupt window would collapse
+    fn is_valid(&self) -> bool {
+        self.min_ratio.is_finite()
+            && self.min_ratio >= Self::MIN_RATIO
+            && self.floor_lux.is_finite()
+            && self.floor_lux >= Self::MIN_FLOOR_LUX
+    }
+}
+
+impl Default for LuxFilter {
+    fn default() -> Self {
+        Self {
+            smoothing: Smoothing::Median(5),
+            min_ratio: 1.1,
+            floor_lux: 1.0,
+        }
+    }
+}
+
+/// Initial sampling schedule, the collector can change it at runtime
+/// see `commands::Command::SetInterval`.
+#[derive(Clone, Serialize, Deserialize, defmt::Format)]
+pub struct Sampling {
+    pub sht31: Schedule,
+    pub bme680: Schedule,
+    pub mhz14: Schedule,
+    pub sps30: Schedule,
+    pub lux_interval_ms: u32,
+    pub lux_filter: LuxFilter,
+    /// The max44009 interrupt pin is wired to PA1, the brightness is then
+    /// only read when it changed instead of every lux interval. Off by
+    /// default, the boards already deployed lack the wire.
+    pub lux_interrupt: bool,
+}
+
+/// Everything that differs between the rooms we deploy this firmware to.
+#[derive(Clone, Serialize, Deserialize, defmt::Format)]
+pub struct NodeConfig {
+    /// Announced over mdns as `<hostname>.local`
+    pub hostname: String<MAX_HOSTNAME>,
+    /// Try dhcp first, the static address is used if no lease is offered
+    pub dhcp: bool,
+    pub address: [u8; 4],
+    pub prefix_len: u8,
+    pub gateway: Option<[u8; 4]>,
+    pub dns_servers: Vec<[u8; 4], 3>,
+    pub collector: Collector,
+    pub transport: Transport,
+    /// Keep (downsampled) readings while the collector is unreachable
+    /// and send them once we reconnect. Needs a collector that asks for
+    /// sequenced frames, a plain one never gets them. Turned off on load
+    /// for the mqtt transport.
+    pub store_and_forward: bool,
+    /// Serve status and metrics over http on this port. Anyone on the
+    /// network can read them, off by default.
+    pub http_port: Option<u16>,
+    /// Set the clock through sntp from this server. Without it readings
+    /// are timestamped with the uptime until the collector sends the time.
+    pub ntp_server: Option<String<64>>,
+    pub sampling: Sampling,
+    pub mac: [u8; 6],
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        let mut dns_servers = Vec::new();
+        unwrap!(dns_servers.push([192, 168, 1, 1]));
+
+        Self {
+            hostname: unwrap!(String::try_from("large-bedroom")),
+            dhcp: true,
+            address: [192, 168, 1, 6],
+            prefix_len: 24,
+            gateway: Some([192, 168, 1, 1]),
+            dns_servers,
+            collector: Collector {
+                host: unwrap!(String::try_from("192.168.1.46")),
+                port: 1234,
+                udp_port: None,
+            },
+            transport: Transport::Collector,
+            store_and_forward: false,
+            http_port: None,
+            ntp_server: Some(unwrap!(String::try_from("pool.ntp.org"))),
+            sampling: Sampling {
+                sht31: Schedule::every(1000),
+                bme680: Schedule::every(1000),
+                mhz14: Schedule::every(1000),
+                sps30: Schedule::every(1000),
+                lux_interval_ms: 50,
+                lux_filter: LuxFilter::default(),
+                lux_interrupt: false,
+            },
+            mac: [0x02, 234, 3, 4, 82, 231],
+        }
+    }
+}
+
+/// Why the config in flash was not used
+#[derive(Debug, defmt::Format)]
+pub enum LoadError {
+    /// No magic at the start, the sector is erased or holds something else
+    NoConfig,
+    /// Stored by firmware with a different layout
+    OtherLayout(u16),
+    Corrupt(postcard::Error),
+}
+
+#[cfg(feature = "board")]
+impl NodeConfig {
+    /// Falls back to the compiled in defaults if flash holds no valid config
+    pub fn load(flash: embassy_stm32::peripherals::FLASH) -> Self {
+        use defmt::{info, warn};
+        use embassy_stm32::flash::Flash;
+
+        let mut flash = Flash::new_blocking(flash);
+        let mut buf = [0u8; STORED_SIZE];
+        if let Err(err) = flash.blocking_read(CONFIG_OFFSET, &mut buf) {
+            warn!("could not read config from flash, using defaults: {}", err);
+            return Self::default();
+        }
+
+        match Self::from_stored(&buf) {
+            Ok(config) => {
+                info!("loaded config from flash: {}", config);
+                config
+            }
+            Err(LoadError::NoConfig) => {
+                info!("no config in flash, using defaults");
+                Self::default()
+            }
+            Err(LoadError::OtherLayout(version)) => {
+                warn!(
+                    "config in flash has layout {} instead of {}, using defaults",
+                    version, LAYOUT_VERSION
+                );
+                Self::default()
+            }
+            Err(LoadError::Corrupt(err)) => {
+                warn!("config in flash is corrupt, using defaults: {}", err);
+                Self::default()
+            }
+        }
+    }
+}
+
+impl NodeConfig {
+    /// Encodes the config as [`NodeConfig::load`] expects it in flash,
+    /// the magic and layout version followed by the postcard encoding.
+    /// Fails if that does not fit `buf` or the space reserved in flash.
+    ///
+    /// To give a node its own config, encode it from a host build of
+    /// the library (a test or small program), write the returned bytes
+    /// to a file and flash that to the start of the last sector:
+    ///
+    /// ```text
+    /// probe-rs download --chip STM32F401CCUx --binary-format bin \
+    ///     --base-address 0x08020000 config.bin
+    /// ```
+    pub fn to_stored<'b>(&self, buf: &'b mut [u8]) -> Result<&'b [u8], postcard::Error> {
+        let len = buf.len().min(STORED_SIZE);
+        let buf = &mut buf[..len];
+        let header = MAGIC.len() + 2;
+        if buf.len() < header {
+            return Err(postcard::Error::SerializeBufferFull);
+        }
+
+        buf[..MAGIC.len()].copy_from_slice(&MAGIC);
+        buf[MAGIC.len()..header].copy_from_slice(&LAYOUT_VERSION.to_le_bytes());
+        let encoded = postcard::to_slice(self, &mut buf[header..])?.len();
+        Ok(&buf[..header + encoded])
+    }
+
+    /// Decodes a config encoded by [`NodeConfig::to_stored`]. An invalid
+    /// lux filter is replaced by the default, store and forward is
+    /// turned off unless the transport is the collector.
+    pub fn from_stored(stored: &[u8]) -> Result<Self, LoadError> {
+        let stored = stored.strip_prefix(&MAGIC).ok_or(LoadError::NoConfig)?;
+        let (version, stored) = stored.split_first_chunk::<2>().ok_or(LoadError::Corrupt(
+            postcard::Error::DeserializeUnexpectedEnd,
+        ))?;
+        let version = u16::from_le_bytes(*version);
+        if version != LAYOUT_VERSION {
+            return Err(LoadError::OtherLayout(version));
+        }
+
+        let mut config = postcard::from_bytes::<Self>(stored).map_err(LoadError::Corrupt)?;
+        let lux_filter = &mut config.sampling.lux_filter;
+        if !lux_filter.is_valid() {
+            defmt::warn!("invalid lux filter, using the default: {}", lux_filter);
+            *lux_filter = LuxFilter::default();
+        }
+        if config.store_and_forward && !matches!(config.transport, Transport::Collector) {
+            defmt::warn!("store and forward needs the collector transport, turned off");
+            config.store_and_forward = false;
+        }
+        Ok(config)
+    }
+}
+
+impl NodeConfig {
+    pub fn network(&self) -> embassy_net::Config {
+        if self.dhcp {
+            embassy_net::Config::dhcpv4(Default::default())
+        } else {
+            embassy_net::Config::ipv4_static(self.static_v4())
+        }
+    }
+
+    pub fn static_v4(&self) -> embassy_net::StaticConfigV4 {
+        embassy_net::StaticConfigV4 {
+            address: Ipv4Cidr::new(Ipv4Address(self.address), self.prefix_len),
+            gateway: self.gateway.map(Ipv4Address),
+            dns_servers: self.dns_servers.iter().copied().map(Ipv4Address).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored(config: &NodeConfig) -> std::vec::Vec<u8> {
+        let mut buf = [0xFF; STORED_SIZE];
+        config.to_stored(&mut buf).unwrap().to_vec()
+    }
+
+    fn room_config() -> NodeConfig {
+        let mut config = NodeConfig::default();
+        config.hostname = String::try_from("study").unwrap();
+        config.dhcp = false;
+        config.address = [10, 0, 0, 7];
+        config.http_port = Some(8080);
+        config.transport = Transport::Mqtt(Broker {
+            host: String::try_from("broker.lan").unwrap(),
+            port: 1883,
+            topic_prefix: String::try_from("study").unwrap(),
+            credentials: None,
+            discovery_prefix: Some(String::try_from("homeassistant").unwrap()),
+        });
+        config
+    }
+
+    #[test]
+    fn round_trip() {
+        let config = room_config();
+        let encoded = stored(&config);
+        assert!(encoded.starts_with(b"NCF1"));
+
+        // flash reads back the whole reserved space
+        let mut flash = [0xFF; STORED_SIZE];
+        flash[..encoded.len()].copy_from_slice(&encoded);
+        let loaded = NodeConfig::from_stored(&flash).unwrap();
+        assert_eq!(stored(&loaded), encoded);
+        assert_eq!(loaded.hostname.as_str(), "study");
+        assert!(!loaded.dhcp);
+    }
+
+    #[test]
+    fn erased_flash_holds_no_config() {
+        let flash = [0xFF; STORED_SIZE];
+        let loaded = NodeConfig::from_stored(&flash);
+        assert!(matches!(loaded, Err(LoadError::NoConfig)));
+    }
+
+    #[test]
+    fn other_layout_is_rejected() {
+        let mut encoded = stored(&room_config());
+        encoded[4..6].copy_from_slice(&(LAYOUT_VERSION + 1).to_le_bytes());
+        let loaded = NodeConfig::from_stored(&encoded);
+        assert!(matches!(loaded, Err(LoadError::OtherLayout(v)) if v == LAYOUT_VERSION + 1));
+    }
+
+    #[test]
+    fn truncated_config_is_corrupt() {
+        let encoded = stored(&room_config());
+        let loaded = NodeConfig::from_stored(&encoded[..encoded.len() / 2]);
+        assert!(matches!(loaded, Err(LoadError::Corrupt(_))));
+        let loaded = NodeConfig::from_stored(&encoded[..5]);
+        assert!(matches!(loaded, Err(LoadError::Corrupt(_))));
+    }
+
+    fn full<const N: usize>() -> String<N> {
+        core::iter::repeat('x').take(N).collect()
+    }
+
+    /// Every string and list filled and every number as large as its
+    /// encoding gets
+    fn largest_config() -> NodeConfig {
+        let schedule = Schedule {
+            interval_ms: u32::MAX,
+            together: true,
+        };
+        NodeConfig {
+            hostname: full(),
+            dhcp: true,
+            address: [255; 4],
+            prefix_len: 32,
+            gateway: Some([255; 4]),
+            dns_servers: Vec::from_slice(&[[255; 4]; 3]).unwrap(),
+            collector: Collector {
+                host: full(),
+                port: u16::MAX,
+                udp_port: Some(u16::MAX),
+            },
+            transport: Transport::Mqtt(Broker {
+                host: full(),
+                port: u16::MAX,
+                topic_prefix: full(),
+                credentials: Some(Credentials {
+                    username: full(),
+                    password: full(),
+                }),
+                discovery_prefix: Some(full()),
+            }),
+            store_and_forward: true,
+            http_port: Some(u16::MAX),
+            ntp_server: Some(full()),
+            sampling: Sampling {
+                sht31: schedule,
+                bme680: schedule,
+                mhz14: schedule,
+                sps30: schedule,
+                lux_interval_ms: u32::MAX,
+                lux_filter: LuxFilter {
+                    smoothing: Smoothing::Median(u8::MAX),
+                    min_ratio: f32::MAX,
+                    floor_lux: f32::MAX,
+                },
+                lux_interrupt: true,
+            },
+            mac: [255; 6],
+        }
+    }
+
+    #[test]
+    fn does_not_exceed_the_reserved_space() {
+        // to_stored never uses more than STORED_SIZE of the buffer
+        let mut buf = [0; STORED_SIZE * 2];
+        let config = largest_config();
+        assert!(config.to_stored(&mut buf).is_ok());
+        assert!(config.to_stored(&mut buf[..16]).is_err());
+    }
+
+    #[test]
+    fn lux_filter_validity() {
+        let valid = LuxFilter::default();
+        assert!(valid.is_valid());
+
+        let at_limits = LuxFilter {
+            min_ratio: LuxFilter::MIN_RATIO,
+            floor_lux: LuxFilter::MIN_FLOOR_LUX,
+            ..valid
+        };
+        assert!(at_limits.is_valid());
+
+        for min_ratio in [1.0, 0.5, f32::NAN, f32::INFINITY] {
+            assert!(!LuxFilter { min_ratio, ..valid }.is_valid());
+        }
+        for floor_lux in [0.0, -1.0, f32::NAN, f32::INFINITY] {
+            assert!(!LuxFilter { floor_lux, ..valid }.is_valid());
+        }
+    }
+
+    #[test]
+    fn invalid_lux_filter_is_replaced() {
+        let mut config = room_config();
+        config.sampling.lux_filter.min_ratio = 1.0;
+        let loaded = NodeConfig::from_stored(&stored(&config)).unwrap();
+        assert_eq!(
+            loaded.sampling.lux_filter.min_ratio,
+            LuxFilter::default().min_ratio
+        );
+    }
+
+    #[test]
+    fn store_and_forward_needs_the_collector() {
+        let mut config = room_config();
+        config.store_and_forward = true;
+        let loaded = NodeConfig::from_stored(&stored(&config)).unwrap();
+        assert!(!loaded.store_and_forward);
+
+        config.transport = Transport::Collector;
+        let loaded = NodeConfig::from_stored(&stored(&config)).unwrap();
+        assert!(loaded.store_and_forward);
+    }
+}
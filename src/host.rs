@@ -0,0 +1,19 @@
+//! Lets the tests run on the host. What is logged goes nowhere.
+
+#[defmt::global_logger]
+struct Logger;
+
+unsafe impl defmt::Logger for Logger {
+    fn acquire() {}
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {}
+
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+#[defmt::panic_handler]
+fn panic() -> ! {
+    panic!("defmt panic")
+}
@@ -0,0 +1,131 @@
+use core::cell::RefCell;
+use core::mem;
+
+use embassy_time::Instant;
+use heapless::Vec;
+use protocol::large_bedroom::{Device, LargeBedroom};
+use protocol::Sensor;
+use serde::{Deserialize, Serialize};
+
+/// More then the number of quantities we measure
+const CAPACITY: usize = 24;
+
+#[derive(Clone)]
+pub struct Latest {
+    pub value: LargeBedroom,
+    pub at: Instant,
+    pub device: Device,
+}
+
+/// The most recent reading of every quantity. Updated by the measure
+/// loops, read by whoever needs the current state rather than a stream.
+pub struct LatestValues {
+    values: RefCell<Vec<Latest, CAPACITY>>,
+}
+
+impl LatestValues {
+    pub fn new() -> Self {
+        Self {
+            values: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn update(&self, device: Device, value: &LargeBedroom) {
+        let latest = Latest {
+            value: value.clone(),
+            at: Instant::now(),
+            device,
+        };
+
+        let mut values = self.values.borrow_mut();
+        let quantity = mem::discriminant(value);
+        match values
+            .iter_mut()
+            .find(|l| mem::discriminant(&l.value) == quantity)
+        {
+            Some(prev) => *prev = latest,
+            None => {
+                let _ignore_full = values.push(latest);
+            }
+        }
+    }
+
+    pub fn all(&self) -> Vec<Latest, CAPACITY> {
+        self.values.borrow().clone()
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let values = self
+            .values
+            .borrow()
+            .iter()
+            .map(|latest| SnapshotEntry {
+                age_ms: latest.at.elapsed().as_millis() as u32,
+                device: latest.device.clone(),
+                value: latest.value.clone(),
+            })
+            .collect();
+        Snapshot { values }
+    }
+}
+
+impl Default for LatestValues {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Name and json payload length for a value, None for values from
+/// other nodes or values that do not fit. The name doubles as the mqtt
+/// topic and is the quantity label of the http metrics.
+pub fn quantity(value: &Sensor, payload: &mut [u8]) -> Option<(&'static str, usize)> {
+    fn json(value: &impl Serialize, payload: &mut [u8]) -> Option<usize> {
+        serde_json_core::to_slice(value, payload).ok()
+    }
+
+    let (name, len) = match value {
+        Sensor::LargeBedroom(reading) => match reading {
+            LargeBedroom::Temperature(v) => ("temperature", json(v, payload)),
+            LargeBedroom::Humidity(v) => ("humidity", json(v, payload)),
+            LargeBedroom::Pressure(v) => ("pressure", json(v, payload)),
+            LargeBedroom::GassResistance(v) => ("gas_resistance", json(v, payload)),
+            LargeBedroom::Co2(v) => ("co2", json(v, payload)),
+            LargeBedroom::MassPm1_0(v) => ("mass_pm1_0", json(v, payload)),
+            LargeBedroom::MassPm2_5(v) => ("mass_pm2_5", json(v, payload)),
+            LargeBedroom::MassPm4_0(v) => ("mass_pm4_0", json(v, payload)),
+            LargeBedroom::MassPm10(v) => ("mass_pm10", json(v, payload)),
+            LargeBedroom::MassPm0_5(v) => ("mass_pm0_5", json(v, payload)),
+            LargeBedroom::NumberPm1_0(v) => ("number_pm1_0", json(v, payload)),
+            LargeBedroom::NumberPm2_5(v) => ("number_pm2_5", json(v, payload)),
+            LargeBedroom::NumberPm4_0(v) => ("number_pm4_0", json(v, payload)),
+            LargeBedroom::NumberPm10(v) => ("number_pm10", json(v, payload)),
+            LargeBedroom::TypicalParticleSize(v) => ("typical_particle_size", json(v, payload)),
+            LargeBedroom::Brightness(v) => ("brightness", json(v, payload)),
+            LargeBedroom::BedButton(v) => ("bed_button", json(v, payload)),
+        },
+        Sensor::LargeBedroomError(err) => ("error", json(err, payload)),
+        _ => return None,
+    };
+    Some((name, len?))
+}
+
+/// Every latest value in one message, send to the collector on request
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    pub values: Vec<SnapshotEntry, CAPACITY>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SnapshotEntry {
+    pub age_ms: u32,
+    pub device: Device,
+    pub value: LargeBedroom,
+}
+
+impl Snapshot {
+    /// An entry takes at most 11 bytes: the age as varint (5), the
+    /// device (1) and the value, a tag and an f32 (5). The spare room
+    /// covers the sequence number, length and cobs overhead. A snapshot
+    /// that still does not fit is dropped, see `network::send_frame`.
+    pub const ENCODED_SIZE: usize = CAPACITY * 20;
+}
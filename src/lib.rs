@@ -0,0 +1,16 @@
+//! Everything but the wiring of the board, that is in `main.rs`. The
+//! parts that only build for the stm32 are behind the `board` feature,
+//! without it the rest builds and is tested on the host.
+#![cfg_attr(not(test), no_std)]
+
+pub mod channel;
+pub mod clock;
+pub mod commands;
+pub mod config;
+pub mod latest;
+pub mod network;
+pub mod sensors;
+pub mod status;
+
+#[cfg(test)]
+mod host;
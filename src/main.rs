@@ -5,11 +5,11 @@ use defmt::{error, info, trace, unwrap};
 use embassy_executor::Spawner;
 use embassy_futures::select::Either;
 use embassy_futures::{join, select};
-use embassy_net::{Ipv4Address, Ipv4Cidr, Stack, StackResources};
+use embassy_net::{Stack, StackResources};
 use embassy_net_wiznet::{chip::W5500, Device, Runner, State};
 use embassy_stm32::interrupt;
 use embassy_stm32::exti::ExtiInput;
-use embassy_stm32::gpio::{Level, Output, Pull, Speed};
+use embassy_stm32::gpio::{Input, Level, Output, Pull, Speed};
 use embassy_stm32::i2c::{self, I2c};
 use embassy_stm32::mode::Async;
 use embassy_stm32::peripherals::{IWDG, SPI1};
@@ -20,20 +20,22 @@ use embassy_stm32::wdg::IndependentWatchdog;
 use embassy_stm32::Config;
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
 use embassy_sync::mutex::Mutex;
-use embassy_sync::signal::Signal;
 use embassy_time::{Delay, Timer};
 use embedded_hal_bus::spi::ExclusiveDevice;
 use futures::{pin_mut, FutureExt};
-use heapless::Vec;
 use rand::{rngs::SmallRng, Rng, SeedableRng};
 use static_cell::StaticCell;
 
 use {defmt_rtt as _, panic_probe as _};
 
-mod channel;
-mod network;
-mod sensors;
-use crate::channel::Channel;
+use large_bed::channel::Channel;
+use large_bed::commands::Commands;
+use large_bed::config::NodeConfig;
+use large_bed::network::Connection;
+use large_bed::sensors::buttons::{AnyInput, ButtonInputs};
+use large_bed::sensors::health::Registry;
+use large_bed::status::ResetReason;
+use large_bed::{network, sensors};
 
 embassy_stm32::bind_interrupts!(struct Irqs {
     I2C1_EV => embassy_stm32::i2c::EventInterruptHandler<embassy_stm32::peripherals::I2C1>;
@@ -72,10 +74,16 @@ async fn net_task(stack: &'static Stack<Device<'static>>) -> ! {
     stack.run().await
 }
 
-async fn gen_random_number() -> u64 {
-    let seed = 5u64;
-    let mut rng = SmallRng::seed_from_u64(seed);
-    let seed = rng.gen();
+/// From the 96 bit unique id of the chip. Differs between nodes so they
+/// do not all pick the same tcp ports and retry connecting at the same
+/// moment.
+fn seed_from_uid() -> u64 {
+    let uid = embassy_stm32::uid::uid();
+    let mut low = [0u8; 8];
+    low.copy_from_slice(&uid[..8]);
+    let mut high = [0u8; 8];
+    high[..4].copy_from_slice(&uid[8..]);
+    let seed = u64::from_le_bytes(low) ^ u64::from_le_bytes(high).rotate_left(32);
     info!("Seed: {}", seed);
     seed
 }
@@ -110,9 +118,15 @@ fn config() -> Config {
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
     let p = embassy_stm32::init(config());
+    let reset_reason = ResetReason::read_and_clear();
+    info!("reset reason: {}", reset_reason);
     let dog = IndependentWatchdog::new(p.IWDG, 20 * 1000 * 1000);
+    let node_config = NodeConfig::load(p.FLASH);
     let publish = Channel::new();
-    let seed = gen_random_number().await;
+    let health = Registry::new();
+    let commands = Commands::new(&node_config.sampling, &health);
+    let connection = Connection::new();
+    let mut rng = SmallRng::seed_from_u64(seed_from_uid());
 
     let mut usart_config = usart::Config::default();
     usart_config.baudrate = 9600;
@@ -151,22 +165,30 @@ async fn main(spawner: Spawner) {
         Irqs,
         p.DMA1_CH7,
         p.DMA1_CH0,
-        // extra slow, helps with longer cable runs
-        Hertz(150_000),
+        sensors::bus::FREQUENCY,
         i2c::Config::default(),
     );
     let i2c: Mutex<NoopRawMutex, _> = Mutex::new(i2c);
 
-    // let buttons = ButtonInputs {
-    //     top_left: ExtiInput::new(p.PA13, p.EXTI13, Pull::Down),
-    //     top_right: ExtiInput::new(p.PA14, p.EXTI14, Pull::Down),
-    //     middle_inner: ExtiInput::new(p.PA9, p.EXTI9, Pull::Down),
-    //     middle_center: ExtiInput::new(p.PA10, p.EXTI10, Pull::Down),
-    //     middle_outer: ExtiInput::new(p.PA11, p.EXTI11, Pull::Down),
-    //     lower_inner: ExtiInput::new(p.PA12, p.EXTI12, Pull::Down),
-    //     lower_center: ExtiInput::new(p.PA15, p.EXTI15, Pull::Down),
-    //     lower_outer: ExtiInput::new(p.PB5, p.EXTI5, Pull::Down),
-    // };
+    // PA13 and PA14 are the debug (SWD) pins, attaching a probe
+    // stops the top buttons from working. They are scanned, that
+    // leaves EXTI13 and EXTI14 free.
+    let buttons = ButtonInputs {
+        top_left: AnyInput::Scanned(Input::new(p.PA13, Pull::Down)),
+        top_right: AnyInput::Scanned(Input::new(p.PA14, Pull::Down)),
+        middle_inner: AnyInput::Exti(ExtiInput::new(p.PA9, p.EXTI9, Pull::Down)),
+        middle_center: AnyInput::Exti(ExtiInput::new(p.PA10, p.EXTI10, Pull::Down)),
+        middle_outer: AnyInput::Exti(ExtiInput::new(p.PA11, p.EXTI11, Pull::Down)),
+        lower_inner: AnyInput::Exti(ExtiInput::new(p.PA12, p.EXTI12, Pull::Down)),
+        lower_center: AnyInput::Exti(ExtiInput::new(p.PA15, p.EXTI15, Pull::Down)),
+        lower_outer: AnyInput::Exti(ExtiInput::new(p.PB5, p.EXTI5, Pull::Down)),
+    };
+
+    // open drain, low while the brightness is outside the window
+    let lux_interrupt = node_config
+        .sampling
+        .lux_interrupt
+        .then(|| ExtiInput::new(p.PA1, p.EXTI1, Pull::Up));
 
     let mut spi_cfg = SpiConfig::default();
     spi_cfg.frequency = Hertz(50_000_000); // up to 50m works
@@ -177,11 +199,10 @@ async fn main(spawner: Spawner) {
     let w5500_int = ExtiInput::new(p.PB0, p.EXTI0, Pull::Up);
     let w5500_reset = Output::new(p.PB1, Level::High, Speed::VeryHigh);
 
-    let mac_addr = [0x02, 234, 3, 4, 82, 231];
     static STATE: StaticCell<State<8, 8>> = StaticCell::new();
     let state = STATE.init(State::<8, 8>::new());
     let (device, runner) = embassy_net_wiznet::new(
-        mac_addr,
+        node_config.mac,
         state,
         ExclusiveDevice::new(spi, cs, Delay),
         w5500_int,
@@ -191,39 +212,71 @@ async fn main(spawner: Spawner) {
     unwrap!(spawner.spawn(ethernet_task(runner)));
 
     // Init network stack
-    let mut dns_servers: Vec<_, 3> = Vec::new();
-    unwrap!(dns_servers.push(Ipv4Address([192, 168, 1, 1])));
-    unwrap!(dns_servers.push(Ipv4Address([192, 168, 1, 1])));
-    unwrap!(dns_servers.push(Ipv4Address([192, 168, 1, 1])));
     static STACK: StaticCell<Stack<Device<'static>>> = StaticCell::new();
-    static RESOURCES: StaticCell<StackResources<2>> = StaticCell::new();
+    // dhcp, dns, mdns, the tcp socket to the collector or broker,
+    // the udp socket for brightness, the http server and sntp
+    static RESOURCES: StaticCell<StackResources<7>> = StaticCell::new();
     let stack = &*STACK.init(Stack::new(
         device,
-        embassy_net::Config::ipv4_static(embassy_net::StaticConfigV4 {
-            address: Ipv4Cidr::new(Ipv4Address([192, 168, 1, 6]), 24),
-            gateway: Some(Ipv4Address([192, 168, 1, 1])),
-            dns_servers,
-        }),
-        RESOURCES.init(StackResources::<2>::new()),
-        seed,
+        node_config.network(),
+        RESOURCES.init(StackResources::<7>::new()),
+        rng.gen(),
     ));
 
     // Launch network task
     unwrap!(spawner.spawn(net_task(stack)));
+    let fell_back_to_static = network::wait_for_ip(stack, &node_config, &publish).await;
 
     embassy_stm32::interrupt::USART6.set_priority(embassy_stm32::interrupt::Priority::P6);
     let spawner = EXECUTOR_HIGH.start(embassy_stm32::interrupt::USART6);
     unwrap!(spawner.spawn(print_if_running_task()));
 
-    let network_up: Signal<NoopRawMutex, ()> = Signal::new();
-    network_up.signal(());
-    let send_published = network::send_published(stack, &publish, &network_up);
+    connection.up.signal(());
+    let send_published = network::send_published(
+        stack,
+        &node_config,
+        &publish,
+        &health,
+        &commands,
+        &connection,
+        rng,
+    );
     pin_mut!(send_published);
     let keep_dog_happy = keep_dog_happy(dog);
-    let send_and_pet_dog = join::join(&mut send_published, keep_dog_happy);
+    let mdns = network::mdns::announce_and_respond(stack, &node_config);
+    let reboot = commands.reboot_when_asked();
+    static HTTP_BUFFERS: StaticCell<network::http::Buffers> = StaticCell::new();
+    let http = async {
+        if let Some(port) = node_config.http_port {
+            let buffers = HTTP_BUFFERS.init_with(network::http::Buffers::new);
+            network::http::serve(stack, port, &publish, &health, reset_reason, buffers).await
+        }
+    };
+    let sntp = async {
+        if let Some(server) = &node_config.ntp_server {
+            network::sntp::keep_clock_set(stack, server, &publish).await
+        }
+    };
+    let dhcp = async {
+        if fell_back_to_static {
+            network::retry_dhcp(stack, &node_config, &publish, &connection).await
+        }
+    };
+    let services = join::join4(mdns, http, sntp, dhcp);
+    let send_and_pet_dog = join::join4(&mut send_published, keep_dog_happy, reboot, services);
 
-    let init_then_measure = sensors::init_then_measure(&publish, i2c, usart_mhz, usart_sps30);
-    let init_then_measure = network_up.wait().then(|_| init_then_measure);
+    let init_then_measure = sensors::init_then_measure(
+        &publish,
+        &health,
+        &commands,
+        i2c,
+        lux_interrupt,
+        usart_mhz,
+        usart_sps30,
+    );
+    let watch_buttons = sensors::buttons::watch(buttons, &publish);
+    let measure = async { join::join(watch_buttons, init_then_measure).await.1 };
+    let init_then_measure = connection.up.wait().then(|_| measure);
     let res = select::select(send_and_pet_dog, init_then_measure).await;
     let unrecoverable_err = match res {
         Either::First(_) => defmt::unreachable!(),
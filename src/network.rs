@@ -1,35 +1,184 @@
-use core::mem;
+use core::cell::Cell;
+use core::future::Future;
 
 use defmt::{info, unwrap, warn};
+use embassy_futures::{join, select};
+use embassy_net::dns::{self, DnsQueryType};
 use embassy_net::driver::Driver;
-use embassy_net::tcp::TcpSocket;
-use embassy_net::{Ipv4Address, Stack};
+use embassy_net::tcp::{self, TcpReader, TcpSocket};
+use embassy_net::{ConfigV4, IpAddress, Stack};
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
 use embassy_sync::signal::Signal;
 use embassy_time::{with_timeout, Duration, Instant, Timer};
-use embedded_io_async::Write;
+use embedded_io_async::{Read, Write};
+use heapless::Vec;
+use postcard::accumulator::{CobsAccumulator, FeedResult};
 use protocol::SensorMessage;
+use rand::rngs::SmallRng;
+use static_cell::StaticCell;
 
-use crate::channel::Channel;
+use crate::channel::{Channel, PriorityValue};
+use crate::clock::SampleTime;
+use crate::commands::Commands;
+use crate::config::{NodeConfig, Transport};
+use crate::latest::Snapshot;
+use crate::sensors::health::Registry;
+use crate::status::Status;
+
+mod backoff;
+pub mod http;
+pub mod link;
+pub mod mdns;
+pub mod mqtt;
+pub mod sntp;
+pub mod store;
+pub mod udp;
+
+use backoff::Backoff;
+use link::{Delivery, Frame, FromCollector, MAX_FRAME};
+use store::Store;
 
 type Msg = SensorMessage<6>;
 
-async fn get_messages(publish: &Channel, msg: &mut Msg) {
+const DHCP_TIMEOUT: Duration = Duration::from_secs(10);
+const FIRST_DHCP_RETRY: Duration = Duration::from_secs(60);
+const MAX_DHCP_RETRY: Duration = Duration::from_secs(30 * 60);
+
+/// State of the connection to the collector or mqtt broker
+pub struct Connection {
+    connected: Cell<bool>,
+    disconnected: Signal<NoopRawMutex, ()>,
+    /// Signalled on every (re)connect
+    pub up: Signal<NoopRawMutex, ()>,
+}
+
+impl Connection {
+    pub fn new() -> Self {
+        Self {
+            connected: Cell::new(false),
+            disconnected: Signal::new(),
+            up: Signal::new(),
+        }
+    }
+
+    /// Kept up to date by the loop sending to the collector or broker
+    fn set_connected(&self, connected: bool) {
+        self.connected.set(connected);
+        if connected {
+            self.up.signal(());
+        } else {
+            self.disconnected.signal(());
+        }
+    }
+
+    /// Returns right away if we are not connected, otherwise once the
+    /// connection drops
+    pub async fn wait_disconnected(&self) {
+        while self.connected.get() {
+            self.disconnected.wait().await;
+        }
+    }
+}
+
+impl Default for Connection {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Waits for a dhcp lease if dhcp is enabled, falls back to the static
+/// address if none comes in time. Reports the address we end up with.
+/// Returns true if we fell back, see [`retry_dhcp`].
+pub async fn wait_for_ip(
+    stack: &Stack<impl Driver>,
+    config: &NodeConfig,
+    publish: &Channel,
+) -> bool {
+    let mut dhcp = config.dhcp;
+    if dhcp
+        && with_timeout(DHCP_TIMEOUT, stack.wait_config_up())
+            .await
+            .is_err()
+    {
+        warn!(
+            "no dhcp lease within {}, using static address",
+            DHCP_TIMEOUT
+        );
+        stack.set_config_v4(ConfigV4::Static(config.static_v4()));
+        dhcp = false;
+    }
+
+    report_address(stack, dhcp, publish);
+    config.dhcp && !dhcp
+}
+
+/// After falling back to the static address, asks for a lease again
+/// every now and then. The static address is gone while we ask, which
+/// would break the connection to the collector or broker. We only ask
+/// while not connected and the wait between attempts grows. Returns
+/// once we got a lease.
+pub async fn retry_dhcp(
+    stack: &Stack<impl Driver>,
+    config: &NodeConfig,
+    publish: &Channel,
+    connection: &Connection,
+) {
+    let mut wait = FIRST_DHCP_RETRY;
+    loop {
+        Timer::after(wait).await;
+        connection.wait_disconnected().await;
+        stack.set_config_v4(ConfigV4::Dhcp(Default::default()));
+        if with_timeout(DHCP_TIMEOUT, stack.wait_config_up())
+            .await
+            .is_ok()
+        {
+            report_address(stack, true, publish);
+            return;
+        }
+
+        stack.set_config_v4(ConfigV4::Static(config.static_v4()));
+        wait = (wait * 2).min(MAX_DHCP_RETRY);
+        warn!("still no dhcp lease, trying again in {}", wait);
+    }
+}
+
+fn report_address(stack: &Stack<impl Driver>, dhcp: bool, publish: &Channel) {
+    let address = unwrap!(stack.config_v4()).address;
+    info!("network up, address: {}, dhcp: {}", address, dhcp);
+    publish.send_status(Status::Network {
+        dhcp,
+        address: address.address().0,
+        prefix_len: address.prefix_len(),
+    });
+}
+
+/// Batches `next` with whatever else is send soon after. Returns true
+/// if any of the messages must arrive even if the connection drops.
+async fn get_messages(
+    next: PriorityValue,
+    publish: &Channel,
+    msg: &mut Msg,
+    sampled_at: &mut Vec<SampleTime, 6>,
+) -> bool {
     msg.values.clear();
-    let next = publish.receive().await;
+    sampled_at.clear();
     let low_priority = next.low_priority();
-    unwrap!(msg.values.push(next.value));
+    let mut must_arrive = next.must_arrive();
+    let add = |value: PriorityValue, msg: &mut Msg, sampled_at: &mut Vec<_, 6>| {
+        unwrap!(sampled_at.push(publish.clock().sample_time(value.at)));
+        unwrap!(msg.values.push(value.value));
+    };
+    add(next, msg, sampled_at);
 
     if low_priority {
         let deadline = Instant::now() + Duration::from_millis(200);
         while msg.space_left() {
             let until = deadline.saturating_duration_since(Instant::now());
             match with_timeout(until, publish.receive()).await {
-                Ok(new) if new.low_priority() => {
-                    unwrap!(msg.values.push(new.value));
-                }
+                Ok(new) if new.low_priority() => add(new, msg, sampled_at),
                 Ok(new) => {
-                    unwrap!(msg.values.push(new.value));
+                    must_arrive |= new.must_arrive();
+                    add(new, msg, sampled_at);
                     break;
                 }
                 Err(_timeout) => break,
@@ -40,47 +189,454 @@ async fn get_messages(publish: &Channel, msg: &mut Msg) {
             let Some(next) = publish.next_ready() else {
                 break;
             };
-            unwrap!(msg.values.push(next.value));
+            must_arrive |= next.must_arrive();
+            add(next, msg, sampled_at);
         }
     }
+    must_arrive
 }
 
+async fn resolve(stack: &Stack<impl Driver>, host: &str) -> Result<IpAddress, dns::Error> {
+    let addresses = stack.dns_query(host, DnsQueryType::A).await?;
+    addresses.first().copied().ok_or(dns::Error::Failed)
+}
+
+/// Sends everything published to the collector or, if configured,
+/// to an mqtt broker. Commands are only accepted from the collector.
+/// Brightness goes to the collector over udp if it has a udp port.
 pub async fn send_published(
     stack: &Stack<impl Driver>,
+    config: &NodeConfig,
     publish: &Channel,
-    network_up: &Signal<NoopRawMutex, ()>,
+    health: &Registry,
+    commands: &Commands<'_>,
+    connection: &Connection,
+    rng: SmallRng,
+) {
+    match &config.transport {
+        Transport::Collector => {
+            // static so it does not take up room in the main future
+            static STORE: StaticCell<Store> = StaticCell::new();
+            let store = config
+                .store_and_forward
+                .then(|| STORE.init_with(Store::new));
+            let tcp = send_to_collector(stack, config, publish, commands, connection, rng, store);
+            let Some(udp_port) = config.collector.udp_port else {
+                return tcp.await;
+            };
+            publish.enable_stream();
+            let udp = udp::send_stream(stack, &config.collector.host, udp_port, publish);
+            join::join(tcp, udp).await;
+        }
+        Transport::Mqtt(broker) => {
+            mqtt::send_published(stack, config, broker, publish, health, connection, rng).await
+        }
+    }
+}
+
+async fn send_to_collector(
+    stack: &Stack<impl Driver>,
+    config: &NodeConfig,
+    publish: &Channel,
+    commands: &Commands<'_>,
+    connection: &Connection,
+    rng: SmallRng,
+    mut store: Option<&mut Store>,
 ) {
     let mut rx_buffer = [0; 800];
     let mut tx_buffer = [0; Msg::ENCODED_SIZE * 4];
 
     let mut msg = Msg::new();
-    let mut encoded_msg_buffer = [0; Msg::ENCODED_SIZE];
+    let delivery = Delivery::new();
 
     let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
     socket.set_timeout(Some(Duration::from_secs(5)));
-    let host_addr = Ipv4Address::new(192, 168, 1, 46);
-    let host_port = 1234;
+    let mut backoff = Backoff::new(rng);
 
     loop {
-        let connected = socket.remote_endpoint().is_some();
-        if !connected {
-            if let Err(e) = socket.connect((host_addr, host_port)).await {
-                warn!("connect error: {:?}", e);
-                Timer::after_secs(1).await;
+        let down_since = Instant::now();
+        let collector = &config.collector;
+        let attempts = connect(
+            stack,
+            &mut socket,
+            (&collector.host, collector.port),
+            publish,
+            &mut backoff,
+            store.as_deref_mut(),
+        )
+        .await;
+
+        info!("(re-)connected");
+        connection.set_connected(true);
+        if let Some(store) = store.as_deref_mut() {
+            while let Some(next) = publish.next_ready() {
+                store.push(next.at, next.value);
+            }
+        }
+        // prevent out-dated data from being send
+        publish.clear().await;
+        publish.send_status(Status::Reconnected {
+            attempts,
+            disconnected_secs: down_since.elapsed().as_secs() as u32,
+            lost_messages: delivery.take_lost(),
+        });
+        backoff.reset();
+
+        let (mut reader, mut writer) = socket.split();
+        let receive = handle_received(&mut reader, publish, &delivery, commands);
+        let send = send_frames(
+            &mut writer,
+            publish,
+            commands,
+            &delivery,
+            store.as_deref_mut(),
+            &mut msg,
+        );
+        select::select(receive, send).await;
+        drop_connection(&mut socket, connection);
+        delivery.connection_lost();
+    }
+}
+
+/// The connection might be half-open, resets it so we reconnect right
+/// away instead of waiting for the timeout
+fn drop_connection(socket: &mut TcpSocket<'_>, connection: &Connection) {
+    socket.abort();
+    connection.set_connected(false);
+}
+
+/// Keeps trying until connected, returns the number of attempts that took.
+/// Meanwhile readings are stored if store and forward is enabled.
+async fn connect(
+    stack: &Stack<impl Driver>,
+    socket: &mut TcpSocket<'_>,
+    (host, port): (&str, u16),
+    publish: &Channel,
+    backoff: &mut Backoff,
+    mut store: Option<&mut Store>,
+) -> u32 {
+    let mut attempts = 0u32;
+    let mut resolve_failure_reported = false;
+    loop {
+        attempts += 1;
+        // resolve every time, the remote might have moved
+        let resolved = storing(resolve(stack, host), publish, store.as_deref_mut()).await;
+        let remote = match resolved {
+            Ok(address) => address,
+            Err(e) => {
+                warn!("could not resolve {}: {:?}", host, e);
+                if !resolve_failure_reported {
+                    publish.send_status(Status::ResolveFailed(e.into()));
+                    resolve_failure_reported = true;
+                }
+                wait_storing(backoff.next(), publish, store.as_deref_mut()).await;
                 continue;
-            } else {
-                info!("(re-)connected");
-                // prevent out-dated data from being send
-                publish.clear().await;
-                network_up.signal(());
             }
+        };
+
+        let connected = storing(
+            socket.connect((remote, port)),
+            publish,
+            store.as_deref_mut(),
+        );
+        match connected.await {
+            Ok(()) => return attempts,
+            Err(e) => {
+                warn!("connect error: {:?}", e);
+                wait_storing(backoff.next(), publish, store.as_deref_mut()).await;
+            }
+        }
+    }
+}
+
+/// Sends plain readings until the collector asks for sequenced frames,
+/// see [`link`] for what only goes out in those. Returns on the first
+/// write error.
+async fn send_frames<W: Write<Error = tcp::Error>>(
+    writer: &mut W,
+    publish: &Channel,
+    commands: &Commands<'_>,
+    delivery: &Delivery,
+    mut store: Option<&mut Store>,
+    msg: &mut Msg,
+) {
+    let mut encoded_msg_buffer = [0; Msg::ENCODED_SIZE];
+    let mut frame_buffer = [0; MAX_FRAME];
+    let mut snapshot_buffer = [0; Snapshot::ENCODED_SIZE];
+    let mut sampled_at = Vec::new();
+    let mut resent = false;
+
+    loop {
+        if !delivery.is_sequenced() {
+            let next = publish.receive();
+            let next = match select::select(next, delivery.sequenced_requested()).await {
+                select::Either::First(next) => next,
+                select::Either::Second(()) => continue,
+            };
+            get_messages(next, publish, msg, &mut sampled_at).await;
+            let to_send = msg.encode_slice(&mut encoded_msg_buffer);
+            if let Err(e) = writer.write_all(to_send).await {
+                warn!("write error: {:?}", e);
+                return;
+            }
+            // the collector can ask while we are writing
+            if !delivery.is_sequenced() {
+                drop_sequenced_only(publish, commands);
+                // nor will it ever take what is stored
+                if let Some(store) = store.as_deref_mut() {
+                    store.clear();
+                }
+            }
+            continue;
         }
 
-        get_messages(publish, &mut msg).await;
-        let to_send = msg.encode_slice(&mut encoded_msg_buffer);
+        // before anything new, an ack for that would drop these
+        if !resent {
+            if let Err(e) = resend_unconfirmed(writer, delivery).await {
+                warn!("write error: {:?}", e);
+                return;
+            }
+            resent = true;
+        }
 
-        if let Err(e) = socket.write_all(to_send).await {
+        let sent = if let Some(event) = publish.next_button() {
+            let button = Frame::Button(event);
+            send_frame(writer, delivery, button, true, &mut frame_buffer).await
+        } else if let Some(replay) = store
+            .as_deref()
+            .filter(|_| delivery.has_room())
+            .and_then(|s| s.peek(publish.clock()))
+        {
+            // kept until acknowledged, only replay as fast as that happens
+            let replayed = Frame::Replayed(replay);
+            let sent = send_frame(writer, delivery, replayed, true, &mut frame_buffer).await;
+            if let (Ok(()), Some(store)) = (&sent, store.as_deref_mut()) {
+                store.pop();
+            }
+            sent
+        } else if let Some(status) = publish.next_status() {
+            let status = Frame::Status(status);
+            send_frame(writer, delivery, status, false, &mut frame_buffer).await
+        } else if commands.snapshot_requested() {
+            let snapshot = Frame::Snapshot(publish.latest().snapshot());
+            send_frame(writer, delivery, snapshot, false, &mut snapshot_buffer).await
+        } else if let Some(ack) = commands.next_ack() {
+            let ack = Frame::CommandAck(ack);
+            send_frame(writer, delivery, ack, true, &mut frame_buffer).await
+        } else {
+            // commands, buttons and the store should not have to wait
+            // for the next reading
+            let next = publish.receive();
+            let next = match select::select4(
+                next,
+                commands.ack_ready(),
+                publish.button_ready(),
+                delivery.acked_some(),
+            )
+            .await
+            {
+                select::Either4::First(next) => next,
+                _ => continue,
+            };
+            let must_arrive = get_messages(next, publish, msg, &mut sampled_at).await;
+            let readings = Frame::Readings {
+                readings: core::mem::replace(msg, Msg::new()),
+                sampled_at: core::mem::take(&mut sampled_at),
+            };
+            send_frame(writer, delivery, readings, must_arrive, &mut frame_buffer).await
+        };
+
+        if let Err(e) = sent {
             warn!("write error: {:?}", e);
+            return;
         }
     }
 }
+
+/// A plain collector can not receive these, drops them instead of
+/// letting them go stale in their queues. The presses still go out as
+/// `BedButton` readings. Statuses are kept, the newest wait for a
+/// collector that asks for sequenced frames.
+fn drop_sequenced_only(publish: &Channel, commands: &Commands<'_>) {
+    while publish.next_button().is_some() {}
+    while commands.next_ack().is_some() {}
+    let _ = commands.snapshot_requested();
+}
+
+/// Frames that do not fit `buf` are dropped
+async fn send_frame<W: Write<Error = tcp::Error>>(
+    writer: &mut W,
+    delivery: &Delivery,
+    frame: Frame,
+    must_arrive: bool,
+    buf: &mut [u8],
+) -> Result<(), tcp::Error> {
+    match delivery.encode(frame, must_arrive, buf) {
+        Ok(encoded) => {
+            let written = writer.write_all(encoded).await;
+            if written.is_err() && !must_arrive {
+                delivery.not_sent();
+            }
+            written
+        }
+        Err(e) => {
+            warn!("frame does not fit, dropping it: {}", e);
+            delivery.not_sent();
+            Ok(())
+        }
+    }
+}
+
+/// Frames from a previous connection the collector did not confirm
+async fn resend_unconfirmed<W: Write<Error = tcp::Error>>(
+    writer: &mut W,
+    delivery: &Delivery,
+) -> Result<(), tcp::Error> {
+    let mut n = 0;
+    while let Some(frame) = delivery.unconfirmed(n) {
+        writer.write_all(&frame).await?;
+        n += 1;
+    }
+    Ok(())
+}
+
+/// Returns once the connection closes or breaks
+async fn handle_received(
+    reader: &mut TcpReader<'_>,
+    publish: &Channel,
+    delivery: &Delivery,
+    commands: &Commands<'_>,
+) {
+    let mut buf = [0; 64];
+    let mut frames = CobsAccumulator::<32>::new();
+
+    loop {
+        let n = match reader.read(&mut buf).await {
+            Ok(0) => {
+                warn!("collector closed the connection");
+                return;
+            }
+            Ok(n) => n,
+            Err(e) => {
+                warn!("read error: {:?}", e);
+                return;
+            }
+        };
+
+        let mut window = &buf[..n];
+        while !window.is_empty() {
+            window = match frames.feed::<FromCollector>(window) {
+                FeedResult::Consumed => break,
+                FeedResult::OverFull(rest) | FeedResult::DeserError(rest) => {
+                    warn!("malformed frame from collector");
+                    rest
+                }
+                FeedResult::Success { data, remaining } => {
+                    match data {
+                        FromCollector::Ack { up_to } => delivery.ack(up_to),
+                        FromCollector::Command { id, command } => commands.handle(id, command),
+                        FromCollector::Time { unix_ms } => publish.clock().set(unix_ms),
+                        FromCollector::UseSequenced => delivery.use_sequenced(),
+                    }
+                    remaining
+                }
+            };
+        }
+    }
+}
+
+/// Waits `duration`, if store and forward is enabled keeps moving readings
+/// into the store meanwhile.
+async fn wait_storing(duration: Duration, publish: &Channel, store: Option<&mut Store>) {
+    storing(Timer::after(duration), publish, store).await
+}
+
+/// Awaits `fut`, if store and forward is enabled keeps moving readings
+/// into the store meanwhile. Resolving and connecting can take longer
+/// then the queue takes to fill up.
+async fn storing<T>(
+    fut: impl Future<Output = T>,
+    publish: &Channel,
+    store: Option<&mut Store>,
+) -> T {
+    let Some(store) = store else {
+        return fut.await;
+    };
+
+    match select::select(fut, store_all(publish, store)).await {
+        select::Either::First(res) => res,
+        select::Either::Second(never) => never,
+    }
+}
+
+async fn store_all(publish: &Channel, store: &mut Store) -> ! {
+    loop {
+        let next = publish.receive().await;
+        store.push(next.at, next.value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+    use protocol::large_bedroom::LargeBedroom;
+
+    use super::*;
+    use crate::status::BusFault;
+
+    const STATUS: Status = Status::I2cBus(BusFault::TransactionsFailing);
+
+    /// Records what is written, asks for sequenced frames while we write
+    struct Collector<'a> {
+        delivery: &'a Delivery,
+        written: std::vec::Vec<u8>,
+    }
+
+    impl embedded_io_async::ErrorType for Collector<'_> {
+        type Error = tcp::Error;
+    }
+
+    impl Write for Collector<'_> {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, tcp::Error> {
+            self.delivery.use_sequenced();
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    #[test]
+    fn sequenced_requested_during_a_write() {
+        let publish = Channel::new();
+        let health = Registry::new();
+        let commands = Commands::new(&NodeConfig::default().sampling, &health);
+        let delivery = Delivery::new();
+
+        let mut buf = [0; MAX_FRAME];
+        let unconfirmed = delivery.encode(Frame::Status(STATUS), true, &mut buf);
+        let unconfirmed = unconfirmed.unwrap().to_vec();
+        delivery.connection_lost();
+
+        publish.send_p2(LargeBedroom::Temperature(20.0));
+        publish.send_status(STATUS);
+
+        let mut collector = Collector {
+            delivery: &delivery,
+            written: std::vec::Vec::new(),
+        };
+        let mut msg = Msg::new();
+        let send = send_frames(
+            &mut collector,
+            &publish,
+            &commands,
+            &delivery,
+            None,
+            &mut msg,
+        );
+        block_on(select::select(send, Timer::after_millis(100)));
+
+        // the plain message, the resend frame then the queued status
+        let frames: std::vec::Vec<_> = collector.written.split_inclusive(|b| *b == 0).collect();
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[1], &unconfirmed[..]);
+    }
+}
@@ -0,0 +1,84 @@
+use embassy_time::Duration;
+use rand::rngs::SmallRng;
+use rand::Rng;
+
+const START: Duration = Duration::from_secs(1);
+const MAX: Duration = Duration::from_secs(60);
+
+/// Exponential backoff with jitter, so nodes that lost the collector at
+/// the same time do not all reconnect at the same moment.
+pub struct Backoff {
+    rng: SmallRng,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(rng: SmallRng) -> Self {
+        Self {
+            rng,
+            current: START,
+        }
+    }
+
+    /// Random duration between half and all of the current backoff,
+    /// the backoff doubles every call.
+    pub fn next(&mut self) -> Duration {
+        let max = self.current.as_millis();
+        let wait = Duration::from_millis(self.rng.gen_range(max / 2..=max));
+        self.current = (self.current * 2).min(MAX);
+        wait
+    }
+
+    pub fn reset(&mut self) {
+        self.current = START;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+
+    fn bounds(backoff: &Backoff) -> (Duration, Duration) {
+        (backoff.current / 2, backoff.current)
+    }
+
+    #[test]
+    fn between_half_and_all_of_a_doubling_wait() {
+        let mut backoff = Backoff::new(SmallRng::seed_from_u64(42));
+        let mut expected_max = START;
+        for _ in 0..20 {
+            let (low, high) = bounds(&backoff);
+            assert_eq!(high, expected_max);
+            let wait = backoff.next();
+            assert!(
+                low <= wait && wait <= high,
+                "{wait:?} not in {low:?}..={high:?}"
+            );
+            expected_max = (expected_max * 2).min(MAX);
+        }
+        assert_eq!(backoff.current, MAX);
+    }
+
+    #[test]
+    fn jitter_differs_between_nodes() {
+        let mut a = Backoff::new(SmallRng::seed_from_u64(1));
+        let mut b = Backoff::new(SmallRng::seed_from_u64(2));
+        let a: std::vec::Vec<_> = (0..8).map(|_| a.next()).collect();
+        let b: std::vec::Vec<_> = (0..8).map(|_| b.next()).collect();
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn reset_after_success() {
+        let mut backoff = Backoff::new(SmallRng::seed_from_u64(7));
+        for _ in 0..10 {
+            backoff.next();
+        }
+        backoff.reset();
+        let wait = backoff.next();
+        assert!(START / 2 <= wait && wait <= START);
+        assert_eq!(backoff.current, START * 2);
+    }
+}
@@ -0,0 +1,306 @@
+//! A minimal http server for looking into a running node without a
+//! probe attached. `GET /metrics` answers in the prometheus text format,
+//! anything else under `/` as json. One connection at a time, every
+//! response closes the connection.
+
+use core::fmt::Write as _;
+
+use defmt::{debug, warn};
+use embassy_net::driver::Driver;
+use embassy_net::tcp::TcpSocket;
+use embassy_net::{Ipv4Cidr, Stack};
+use embassy_time::{Duration, Instant};
+use embedded_io_async::{Read, Write};
+use heapless::String;
+use protocol::Sensor;
+use serde::Serialize;
+
+use crate::channel::Channel;
+use crate::latest::quantity;
+use crate::sensors::health::Registry;
+use crate::status::ResetReason;
+
+/// Large enough for every quantity, error count and the node info
+const MAX_BODY: usize = 2048;
+
+/// Static so they do not take up room in the main future
+pub struct Buffers {
+    rx: [u8; 512],
+    tx: [u8; 1024],
+    request: [u8; 512],
+    body: String<MAX_BODY>,
+}
+
+impl Buffers {
+    pub const fn new() -> Self {
+        Self {
+            rx: [0; 512],
+            tx: [0; 1024],
+            request: [0; 512],
+            body: String::new(),
+        }
+    }
+}
+
+enum Format {
+    Prometheus,
+    Json,
+}
+
+pub async fn serve(
+    stack: &Stack<impl Driver>,
+    port: u16,
+    publish: &Channel,
+    health: &Registry,
+    reset_reason: ResetReason,
+    buffers: &mut Buffers,
+) {
+    let Buffers {
+        rx,
+        tx,
+        request,
+        body,
+    } = buffers;
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx[..], &mut tx[..]);
+        socket.set_timeout(Some(Duration::from_secs(5)));
+        if let Err(e) = socket.accept(port).await {
+            warn!("http accept error: {:?}", e);
+            continue;
+        }
+
+        let Some(path) = read_path(&mut socket, request).await else {
+            socket.abort();
+            continue;
+        };
+        debug!("http request for {}", path);
+
+        let format = match path {
+            "/metrics" => Some(Format::Prometheus),
+            "/" | "/status" => Some(Format::Json),
+            _ => None,
+        };
+
+        body.clear();
+        let link_up = stack.is_link_up();
+        let address = stack.config_v4().map(|config| config.address);
+        let written = match format {
+            Some(Format::Prometheus) => prometheus(body, link_up, publish, health, reset_reason),
+            Some(Format::Json) => json(body, link_up, address, publish, health, reset_reason),
+            None => Ok(()),
+        };
+        if written.is_err() {
+            warn!("http response does not fit, truncated");
+        }
+
+        let (status, content_type) = match format {
+            Some(Format::Prometheus) => ("200 OK", "text/plain; version=0.0.4"),
+            Some(Format::Json) => ("200 OK", "application/json"),
+            None => ("404 Not Found", "text/plain"),
+        };
+        let mut head = String::<128>::new();
+        let _ = write!(
+            head,
+            "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\n\
+            Content-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        );
+
+        let sent = async {
+            socket.write_all(head.as_bytes()).await?;
+            socket.write_all(body.as_bytes()).await?;
+            socket.flush().await
+        };
+        if let Err(e) = sent.await {
+            warn!("http write error: {:?}", e);
+            socket.abort();
+            continue;
+        }
+        socket.close();
+        // wait for the client to receive everything and hang up
+        let _ = socket.flush().await;
+    }
+}
+
+/// Path of a GET request, None if it is something else or incomplete
+async fn read_path<'a>(socket: &mut TcpSocket<'_>, buf: &'a mut [u8]) -> Option<&'a str> {
+    let mut filled = 0;
+    loop {
+        if buf[..filled].windows(4).any(|w| w == b"\r\n\r\n") {
+            break;
+        }
+        if filled == buf.len() {
+            // the headers do not fit, the request line probably does
+            break;
+        }
+        match socket.read(&mut buf[filled..]).await {
+            Ok(0) => return None,
+            Ok(n) => filled += n,
+            Err(e) => {
+                warn!("http read error: {:?}", e);
+                return None;
+            }
+        }
+    }
+
+    let request_line = buf[..filled].split(|b| *b == b'\r').next()?;
+    let request_line = core::str::from_utf8(request_line).ok()?;
+    let mut parts = request_line.split(' ');
+    if parts.next()? != "GET" {
+        return None;
+    }
+    let target = parts.next()?;
+    target.split('?').next()
+}
+
+/// Json representation of a serializable value without allocating
+fn as_json<const N: usize>(value: &impl Serialize) -> String<N> {
+    let mut buf = [0; N];
+    let len = serde_json_core::to_slice(value, &mut buf).unwrap_or(0);
+    let mut json = String::new();
+    let _ = json.push_str(core::str::from_utf8(&buf[..len]).unwrap_or(""));
+    json
+}
+
+fn prometheus(
+    body: &mut String<MAX_BODY>,
+    link_up: bool,
+    publish: &Channel,
+    health: &Registry,
+    reset_reason: ResetReason,
+) -> core::fmt::Result {
+    let uptime = Instant::now().as_secs();
+    // starts over at every reset, a counter would be read as restarts
+    writeln!(body, "# TYPE node_uptime_seconds gauge")?;
+    writeln!(body, "node_uptime_seconds {uptime}")?;
+    let reason = as_json::<24>(&reset_reason);
+    writeln!(body, "node_reset_reason{{reason={reason}}} 1")?;
+    writeln!(body, "node_link_up {}", link_up as u8)?;
+
+    writeln!(body, "# TYPE sensor_value gauge")?;
+    let mut payload = [0; 64];
+    for latest in publish.latest().all() {
+        let value = Sensor::LargeBedroom(latest.value);
+        let Some((name, len)) = quantity(&value, &mut payload) else {
+            continue;
+        };
+        // only plain numbers, button presses are not a gauge
+        let Ok(number) = core::str::from_utf8(&payload[..len]) else {
+            continue;
+        };
+        if number.parse::<f32>().is_err() {
+            continue;
+        }
+        writeln!(body, "sensor_value{{quantity=\"{name}\"}} {number}")?;
+    }
+
+    writeln!(body, "# TYPE sensor_errors_total counter")?;
+    for (device, count) in health.error_counts() {
+        let device = as_json::<16>(&device);
+        writeln!(body, "sensor_errors_total{{device={device}}} {count}")?;
+    }
+    Ok(())
+}
+
+fn json(
+    body: &mut String<MAX_BODY>,
+    link_up: bool,
+    address: Option<Ipv4Cidr>,
+    publish: &Channel,
+    health: &Registry,
+    reset_reason: ResetReason,
+) -> core::fmt::Result {
+    write!(body, "{{\"uptime_s\":{}", Instant::now().as_secs())?;
+    write!(body, ",\"reset_reason\":{}", as_json::<24>(&reset_reason))?;
+    write!(body, ",\"link_up\":{link_up}")?;
+    match address {
+        Some(address) => write!(body, ",\"address\":\"{address}\"")?,
+        None => write!(body, ",\"address\":null")?,
+    }
+
+    write!(body, ",\"values\":{{")?;
+    let mut payload = [0; 64];
+    let mut first = true;
+    for latest in publish.latest().all() {
+        let value = Sensor::LargeBedroom(latest.value);
+        let Some((name, len)) = quantity(&value, &mut payload) else {
+            continue;
+        };
+        let Ok(value) = core::str::from_utf8(&payload[..len]) else {
+            continue;
+        };
+        let separator = if first { "" } else { "," };
+        write!(body, "{separator}\"{name}\":{value}")?;
+        first = false;
+    }
+
+    write!(body, "}},\"errors\":{{")?;
+    for (i, (device, count)) in health.error_counts().iter().enumerate() {
+        let separator = if i == 0 { "" } else { "," };
+        write!(body, "{separator}{}:{count}", as_json::<16>(device))?;
+    }
+
+    write!(body, "}},\"health\":{{")?;
+    for (i, (device, state)) in health.all().iter().enumerate() {
+        let separator = if i == 0 { "" } else { "," };
+        let state = as_json::<24>(state);
+        write!(body, "{separator}{}:{state}", as_json::<16>(device))?;
+    }
+    write!(body, "}}}}")
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_net::Ipv4Address;
+    use protocol::large_bedroom::{Device, Error, LargeBedroom as LB};
+
+    use super::*;
+    use crate::sensors::health::Supervisor;
+
+    fn node() -> (Channel, Registry) {
+        let publish = Channel::new();
+        let latest = publish.latest();
+        latest.update(Device::Sht31, &LB::Temperature(21.5));
+        latest.update(Device::Max44, &LB::Brightness(300.0));
+        let health = Registry::new();
+        let supervisor = Supervisor::<()>::new(Device::Mhz14, &health);
+        let _ = supervisor.record(false, &publish);
+        supervisor.report(Error::Timeout(Device::Mhz14), &publish);
+        (publish, health)
+    }
+
+    #[test]
+    fn prometheus_metrics() {
+        let (publish, health) = node();
+        let mut body = String::new();
+        prometheus(&mut body, true, &publish, &health, ResetReason::Watchdog).unwrap();
+        let lines: std::vec::Vec<_> = body.lines().collect();
+
+        assert!(lines.contains(&"# TYPE node_uptime_seconds gauge"));
+        assert!(lines.contains(&"node_reset_reason{reason=\"Watchdog\"} 1"));
+        assert!(lines.contains(&"node_link_up 1"));
+        assert!(lines.contains(&"sensor_value{quantity=\"temperature\"} 21.5"));
+        assert!(lines.contains(&"sensor_value{quantity=\"brightness\"} 300.0"));
+        assert!(lines.contains(&"sensor_errors_total{device=\"Mhz14\"} 1"));
+    }
+
+    #[test]
+    fn json_status() {
+        let (publish, health) = node();
+        let address = Some(Ipv4Cidr::new(Ipv4Address::new(192, 168, 1, 6), 24));
+        let reason = ResetReason::PowerOn;
+        let mut body = String::new();
+        json(&mut body, false, address, &publish, &health, reason).unwrap();
+
+        assert!(body.starts_with("{\"uptime_s\":"));
+        assert!(body.contains(",\"reset_reason\":\"PowerOn\",\"link_up\":false"));
+        assert!(body.contains(",\"address\":\"192.168.1.6/24\""));
+        assert!(body.contains(",\"values\":{\"temperature\":21.5,\"brightness\":300.0}"));
+        assert!(body.ends_with(",\"errors\":{\"Mhz14\":1},\"health\":{\"Mhz14\":\"Degraded\"}}"));
+
+        body.clear();
+        json(&mut body, false, None, &publish, &health, reason).unwrap();
+        assert!(body.contains(",\"address\":null,"));
+    }
+}
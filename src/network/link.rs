@@ -0,0 +1,292 @@
+//! Framing on the tcp connection to the collector. Until the collector
+//! asks for more we send what every collector understands: postcard +
+//! cobs encoded `SensorMessage`s. A collector that wants sample times,
+//! status, acknowledgements and commands sends
+//! [`FromCollector::UseSequenced`], from then on until the connection
+//! drops everything we send is a [`Sequenced`] frame in the same encoding.
+//!
+//! Only sequenced frames carry statuses, gestures, command
+//! acknowledgements, snapshots and stored readings. A collector that
+//! wants those sends `UseSequenced` right after connecting. Once the
+//! first plain message went out whatever of these is queued is dropped,
+//! stored readings included. Store and forward therefore needs a
+//! collector that opts in. Statuses are the exception: the newest wait
+//! for a collector that opts in, see
+//! [`Channel::send_status`](crate::channel::Channel::send_status).
+//!
+//! These types are not in the `protocol` crate: that only knows
+//! `SensorMessage` and is shared with nodes that do not speak this. The
+//! collector gets them, and the [`Datagram`](super::udp::Datagram), from
+//! this library built without the `board` feature. Every type on the
+//! wire derives both `Serialize` and `Deserialize` for that. Plain
+//! messages can not carry sample times, the collector stamps those on
+//! arrival.
+
+use core::cell::{Cell, RefCell};
+
+use defmt::warn;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::signal::Signal;
+use heapless::{Deque, Vec};
+use serde::{Deserialize, Serialize};
+
+use super::store::Replayed;
+use super::Msg;
+use crate::clock::SampleTime;
+use crate::commands::{Ack, Command};
+use crate::latest::Snapshot;
+use crate::sensors::buttons::ButtonEvent;
+use crate::status::Status;
+
+/// Upper bound for any encoded frame except the snapshot. The readings
+/// frame is the largest, this leaves room for their sample times, the
+/// sequence number and the cobs overhead.
+pub const MAX_FRAME: usize = Msg::ENCODED_SIZE + 6 * SampleTime::MAX_SIZE + 16;
+
+#[derive(Serialize, Deserialize)]
+pub enum Frame {
+    Readings {
+        readings: Msg,
+        /// One for every value in the message, in the same order
+        sampled_at: Vec<SampleTime, 6>,
+    },
+    Status(Status),
+    Replayed(Replayed),
+    CommandAck(Ack),
+    /// Too large for [`MAX_FRAME`], never needs to be resend
+    Snapshot(Snapshot),
+    Button(ButtonEvent),
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Sequenced {
+    pub seq: u32,
+    pub frame: Frame,
+}
+
+#[derive(Serialize, Deserialize, defmt::Format)]
+pub enum FromCollector {
+    /// Every frame up to and including `up_to` arrived
+    Ack { up_to: u32 },
+    /// Answered with a [`Frame::CommandAck`] carrying the same id
+    Command { id: u32, command: Command },
+    /// The current time in milliseconds since the unix epoch, for nodes
+    /// that can not reach an ntp server
+    Time { unix_ms: u64 },
+    /// Switch from plain `SensorMessage`s to [`Sequenced`] frames
+    UseSequenced,
+}
+
+/// Numbers outgoing frames and tracks which ones the collector confirmed.
+/// Frames that must arrive are kept until confirmed so they can be
+/// send again on the next connection.
+pub struct Delivery {
+    next_seq: Cell<u32>,
+    unconfirmed: RefCell<Deque<(u32, Vec<u8, MAX_FRAME>), 4>>,
+    lost: Cell<u32>,
+    /// The collector asked for sequenced frames on this connection
+    sequenced: Cell<bool>,
+    sequenced_requested: Signal<NoopRawMutex, ()>,
+    acked_some: Signal<NoopRawMutex, ()>,
+}
+
+impl Delivery {
+    pub fn new() -> Self {
+        Self {
+            next_seq: Cell::new(1),
+            unconfirmed: RefCell::new(Deque::new()),
+            lost: Cell::new(0),
+            sequenced: Cell::new(false),
+            sequenced_requested: Signal::new(),
+            acked_some: Signal::new(),
+        }
+    }
+
+    pub fn use_sequenced(&self) {
+        self.sequenced.set(true);
+        self.sequenced_requested.signal(());
+    }
+
+    pub fn is_sequenced(&self) -> bool {
+        self.sequenced.get()
+    }
+
+    /// Returns once the collector asks for sequenced frames, can
+    /// return spuriously
+    pub async fn sequenced_requested(&self) {
+        self.sequenced_requested.wait().await
+    }
+
+    /// Returns the encoded frame, if `must_arrive` it is kept until the
+    /// collector acknowledges it. Fails if the frame does not fit `buf`.
+    pub fn encode<'b>(
+        &self,
+        frame: Frame,
+        must_arrive: bool,
+        buf: &'b mut [u8],
+    ) -> Result<&'b [u8], postcard::Error> {
+        let seq = self.next_seq.get();
+        let encoded = postcard::to_slice_cobs(&Sequenced { seq, frame }, buf)?;
+        self.next_seq.set(seq.wrapping_add(1));
+
+        if must_arrive {
+            let mut unconfirmed = self.unconfirmed.borrow_mut();
+            if unconfirmed.is_full() {
+                // it might still have arrived, it is not counted as lost
+                warn!("too many unconfirmed messages, not sending the oldest again");
+                unconfirmed.pop_front();
+            }
+            match Vec::from_slice(encoded) {
+                Ok(copy) => {
+                    let _cant_be_full = unconfirmed.push_back((seq, copy));
+                }
+                Err(()) => {
+                    warn!("frame too large to send again, it might get lost");
+                }
+            }
+        }
+        Ok(encoded)
+    }
+
+    pub fn ack(&self, up_to: u32) {
+        let mut unconfirmed = self.unconfirmed.borrow_mut();
+        while unconfirmed.front().is_some_and(|(seq, _)| *seq <= up_to) {
+            unconfirmed.pop_front();
+        }
+        self.acked_some.signal(());
+    }
+
+    /// A frame that must arrive can be kept without dropping an older one
+    pub fn has_room(&self) -> bool {
+        !self.unconfirmed.borrow().is_full()
+    }
+
+    /// Returns once the collector acknowledged frames, can return
+    /// spuriously
+    pub async fn acked_some(&self) {
+        self.acked_some.wait().await
+    }
+
+    /// The next connection starts out plain. Frames that were send but
+    /// not acknowledged might still have arrived, they are not counted
+    /// as lost.
+    pub fn connection_lost(&self) {
+        self.sequenced.set(false);
+        self.sequenced_requested.reset();
+    }
+
+    /// A frame that never (fully) made it onto the connection and will
+    /// not be send again
+    pub fn not_sent(&self) {
+        self.lost.set(self.lost.get() + 1);
+    }
+
+    /// Messages known lost since the last call
+    pub fn take_lost(&self) -> u32 {
+        self.lost.take()
+    }
+
+    /// Copy of the nth unconfirmed frame, oldest first
+    pub fn unconfirmed(&self, n: usize) -> Option<Vec<u8, MAX_FRAME>> {
+        self.unconfirmed
+            .borrow()
+            .iter()
+            .nth(n)
+            .map(|(_, frame)| frame.clone())
+    }
+}
+
+impl Default for Delivery {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::status::BusFault;
+
+    const STATUS: Status = Status::I2cBus(BusFault::TransactionsFailing);
+
+    fn send(delivery: &Delivery, must_arrive: bool) -> u32 {
+        #[derive(Deserialize)]
+        struct Seq {
+            seq: u32,
+        }
+
+        let mut buf = [0; MAX_FRAME];
+        let encoded = delivery.encode(Frame::Status(STATUS), must_arrive, &mut buf);
+        let mut encoded: Vec<u8, MAX_FRAME> = Vec::from_slice(encoded.unwrap()).unwrap();
+        postcard::from_bytes_cobs::<Seq>(&mut encoded).unwrap().seq
+    }
+
+    fn kept(delivery: &Delivery) -> usize {
+        (0..)
+            .take_while(|n| delivery.unconfirmed(*n).is_some())
+            .count()
+    }
+
+    #[test]
+    fn numbered_from_one_across_connections() {
+        let delivery = Delivery::new();
+        assert_eq!(send(&delivery, false), 1);
+        assert_eq!(send(&delivery, true), 2);
+        delivery.connection_lost();
+        assert_eq!(send(&delivery, false), 3);
+    }
+
+    #[test]
+    fn kept_until_acknowledged() {
+        let delivery = Delivery::new();
+        send(&delivery, true);
+        send(&delivery, false);
+        send(&delivery, true);
+        assert_eq!(kept(&delivery), 2);
+
+        delivery.ack(2);
+        assert_eq!(kept(&delivery), 1);
+        delivery.ack(3);
+        assert_eq!(kept(&delivery), 0);
+    }
+
+    #[test]
+    fn window_drops_the_oldest() {
+        let delivery = Delivery::new();
+        for _ in 0..4 {
+            send(&delivery, true);
+        }
+        assert!(!delivery.has_room());
+        let second = delivery.unconfirmed(1);
+
+        send(&delivery, true);
+        assert_eq!(kept(&delivery), 4);
+        assert_eq!(delivery.unconfirmed(0), second);
+        assert_eq!(delivery.take_lost(), 0);
+    }
+
+    #[test]
+    fn only_frames_not_sent_are_lost() {
+        let delivery = Delivery::new();
+        send(&delivery, false);
+        send(&delivery, true);
+        delivery.connection_lost();
+        assert_eq!(delivery.take_lost(), 0);
+        assert_eq!(kept(&delivery), 1);
+
+        delivery.not_sent();
+        assert_eq!(delivery.take_lost(), 1);
+        assert_eq!(delivery.take_lost(), 0);
+    }
+
+    #[test]
+    fn plain_again_after_reconnecting() {
+        let delivery = Delivery::new();
+        delivery.use_sequenced();
+        assert!(delivery.is_sequenced());
+        delivery.connection_lost();
+        assert!(!delivery.is_sequenced());
+    }
+}
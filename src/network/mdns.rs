@@ -0,0 +1,482 @@
+//! Announces the node as `<hostname>.local`, then answers queries for
+//! it. The node is also announced as an instance of the `_envsensor._tcp`
+//! dns-sd service so the collector can find it, and if the http server
+//! is enabled as an instance of `_http._tcp`. Announced again whenever
+//! our address changes, not at all while we have none.
+//! The node serves nothing for `_envsensor._tcp`, it connects out to the
+//! collector. Its instance therefore has port 0 and carries the endpoint
+//! it sends to as `collector=<host>:<port>` in the txt record.
+//! Packet building and parsing only touch byte slices, the tests check
+//! them against the dns parser of the `simple-dns` crate.
+
+use core::fmt::Write as _;
+
+use defmt::{debug, info, warn};
+use embassy_futures::select::{select, Either};
+use embassy_net::driver::Driver;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpEndpoint, Ipv4Address, Stack};
+use embassy_time::{Duration, Timer};
+use heapless::{String, Vec};
+
+use crate::config::{Collector, NodeConfig};
+
+const PORT: u16 = 5353;
+const GROUP: Ipv4Address = Ipv4Address::new(224, 0, 0, 251);
+
+/// A dns-sd service we announce an instance of
+struct Service {
+    name: [&'static str; 3],
+    txt: &'static [&'static str],
+}
+
+/// Our own protocol, announced with port 0 as we do not listen for it
+const SENSOR: Service = Service {
+    name: ["_envsensor", "_tcp", "local"],
+    // which readings to expect, see the `protocol` crate
+    txt: &["protocol=large_bedroom"],
+};
+const HTTP: Service = Service {
+    name: ["_http", "_tcp", "local"],
+    // the page to open, see `network::http`
+    txt: &["path=/status"],
+};
+const SERVICES_META: [&str; 4] = ["_services", "_dns-sd", "_udp", "local"];
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Tells caches to replace rather than add to what they have
+const CACHE_FLUSH: u16 = 0x8000;
+
+const HOST_TTL: u32 = 120;
+const SERVICE_TTL: u32 = 4500;
+/// How often we check if dhcp gave us a different address
+const ADDRESS_POLL: Duration = Duration::from_secs(5);
+
+pub async fn announce_and_respond(stack: &Stack<impl Driver>, config: &NodeConfig) {
+    if let Err(e) = stack.join_multicast_group(GROUP).await {
+        warn!("could not join mdns multicast group: {:?}", e);
+        return;
+    }
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; 512];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buffer = [0; 512];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(PORT) {
+        warn!("could not bind mdns socket: {:?}", e);
+        return;
+    }
+
+    let hostname = config.hostname.as_str();
+    let collector = &config.collector;
+    let http_port = config.http_port;
+    let mut packet = [0; 512];
+    let group = IpEndpoint::new(GROUP.into(), PORT);
+
+    loop {
+        let Some(announced) = our_address(stack) else {
+            Timer::after(ADDRESS_POLL).await;
+            continue;
+        };
+        // rfc 6762 8.3, announce at least twice one second apart
+        for _ in 0..3 {
+            let len = announcement(&mut packet, hostname, announced, collector, http_port);
+            if let Err(e) = socket.send_to(&packet[..len], group).await {
+                warn!("could not send mdns announcement: {:?}", e);
+            }
+            Timer::after_secs(1).await;
+        }
+        info!("announced as {}.local", hostname);
+
+        while our_address(stack) == Some(announced) {
+            let received = select(socket.recv_from(&mut packet), Timer::after(ADDRESS_POLL));
+            let len = match received.await {
+                Either::First(Ok((len, _))) => len,
+                Either::First(Err(e)) => {
+                    warn!("mdns receive error: {:?}", e);
+                    continue;
+                }
+                Either::Second(()) => continue,
+            };
+
+            if !asks_for_us(&packet[..len], hostname, http_port.is_some()) {
+                continue;
+            }
+
+            debug!("answering mdns query");
+            let len = announcement(&mut packet, hostname, announced, collector, http_port);
+            if let Err(e) = socket.send_to(&packet[..len], group).await {
+                warn!("could not send mdns response: {:?}", e);
+            }
+        }
+    }
+}
+
+/// None until dhcp gave us an address
+fn our_address(stack: &Stack<impl Driver>) -> Option<[u8; 4]> {
+    stack
+        .config_v4()
+        .map(|config| config.address.address().0)
+        .filter(|address| *address != [0; 4])
+}
+
+/// `<hostname>.<service>`
+fn instance<'a>(hostname: &'a str, service: &Service) -> [&'a str; 4] {
+    let [kind, proto, domain] = service.name;
+    [hostname, kind, proto, domain]
+}
+
+/// Response with all our records. Used both for unsolicited announcements
+/// and as answer to any query concerning us. The http service is only
+/// included if it has a port. Returns the length.
+pub fn announcement(
+    buf: &mut [u8],
+    hostname: &str,
+    address: [u8; 4],
+    collector: &Collector,
+    http_port: Option<u16>,
+) -> usize {
+    let host = [hostname, "local"];
+    // "collector=" + host + ":" + port always fits
+    let mut endpoint = String::<80>::new();
+    let _ = write!(endpoint, "collector={}:{}", collector.host, collector.port);
+
+    let mut w = Writer { buf, len: 0 };
+    w.u16(0); // id, zero for mdns
+    w.u16(0x8400); // response, authoritative
+    w.u16(0); // questions
+    w.u16(if http_port.is_some() { 9 } else { 5 }); // answers
+    w.u16(0); // authority records
+    w.u16(0); // additional records
+
+    w.record(&host, TYPE_A, CLASS_IN | CACHE_FLUSH, HOST_TTL, |w| {
+        w.bytes(&address)
+    });
+    w.service(&SENSOR, hostname, 0, Some(endpoint.as_str()));
+    if let Some(port) = http_port {
+        w.service(&HTTP, hostname, port, None);
+    }
+    w.len
+}
+
+/// True if the packet is a query with a question about our host, our
+/// service instances, their service types or all services. The http
+/// service only counts if we `serve_http`.
+pub fn asks_for_us(packet: &[u8], hostname: &str, serve_http: bool) -> bool {
+    let host = [hostname, "local"];
+    let services = [Some(&SENSOR), serve_http.then_some(&HTTP)];
+    let services = || services.iter().flatten();
+    let is_type = |name: &[&[u8]]| services().any(|s| name_eq(name, &s.name));
+    let is_instance = |name: &[&[u8]]| services().any(|s| name_eq(name, &instance(hostname, s)));
+
+    let Some(header) = packet.get(..12) else {
+        return false;
+    };
+    let is_response = header[2] & 0x80 != 0;
+    if is_response {
+        return false;
+    }
+    let questions = u16::from_be_bytes([header[4], header[5]]);
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        let Some((name, end)) = read_name(packet, pos) else {
+            return false;
+        };
+        let Some(qtype) = packet.get(end..end + 2) else {
+            return false;
+        };
+        let qtype = u16::from_be_bytes([qtype[0], qtype[1]]);
+        pos = end + 4; // type and class
+
+        let wanted = match qtype {
+            TYPE_A => name_eq(&name, &host),
+            TYPE_PTR => is_type(&name) || name_eq(&name, &SERVICES_META),
+            TYPE_SRV | TYPE_TXT => is_instance(&name),
+            TYPE_ANY => name_eq(&name, &host) || is_instance(&name),
+            _ => false,
+        };
+        if wanted {
+            return true;
+        }
+    }
+    false
+}
+
+type Labels<'a> = Vec<&'a [u8], 8>;
+
+/// Follows compression pointers. Returns the labels and the position
+/// just after the name.
+fn read_name(packet: &[u8], mut pos: usize) -> Option<(Labels<'_>, usize)> {
+    let mut labels = Labels::new();
+    let mut end = None;
+    // bound the number of pointers, a malicious packet could loop
+    for _ in 0..16 {
+        let len = *packet.get(pos)? as usize;
+        if len == 0 {
+            return Some((labels, end.unwrap_or(pos + 1)));
+        } else if len & 0xC0 == 0xC0 {
+            let low = *packet.get(pos + 1)? as usize;
+            end.get_or_insert(pos + 2);
+            pos = ((len & 0x3F) << 8) | low;
+        } else {
+            let label = packet.get(pos + 1..pos + 1 + len)?;
+            labels.push(label).ok()?;
+            pos += 1 + len;
+        }
+    }
+    None
+}
+
+fn name_eq(labels: &[&[u8]], name: &[&str]) -> bool {
+    labels.len() == name.len()
+        && labels
+            .iter()
+            .zip(name)
+            .all(|(a, b)| a.eq_ignore_ascii_case(b.as_bytes()))
+}
+
+/// Writes a dns message. Silently truncates if the buffer is to small,
+/// our records fit easily in the buffers used here.
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn bytes(&mut self, bytes: &[u8]) {
+        let end = (self.len + bytes.len()).min(self.buf.len());
+        let n = end - self.len;
+        self.buf[self.len..end].copy_from_slice(&bytes[..n]);
+        self.len = end;
+    }
+
+    fn u16(&mut self, val: u16) {
+        self.bytes(&val.to_be_bytes())
+    }
+
+    fn u32(&mut self, val: u32) {
+        self.bytes(&val.to_be_bytes())
+    }
+
+    fn label(&mut self, label: &str) {
+        self.bytes(&[label.len() as u8]);
+        self.bytes(label.as_bytes());
+    }
+
+    fn name(&mut self, name: &[&str]) {
+        for label in name {
+            self.label(label);
+        }
+        self.bytes(&[0]);
+    }
+
+    /// The records that make up an instance of `service` on `port`, with
+    /// an optional `extra` txt entry
+    fn service(&mut self, service: &Service, hostname: &str, port: u16, extra: Option<&str>) {
+        let host = [hostname, "local"];
+        let instance = instance(hostname, service);
+
+        self.record(&SERVICES_META, TYPE_PTR, CLASS_IN, SERVICE_TTL, |w| {
+            w.name(&service.name)
+        });
+        self.record(&service.name, TYPE_PTR, CLASS_IN, SERVICE_TTL, |w| {
+            w.name(&instance)
+        });
+        self.record(&instance, TYPE_SRV, CLASS_IN | CACHE_FLUSH, HOST_TTL, |w| {
+            w.u16(0); // priority
+            w.u16(0); // weight
+            w.u16(port);
+            w.name(&host);
+        });
+        self.record(
+            &instance,
+            TYPE_TXT,
+            CLASS_IN | CACHE_FLUSH,
+            SERVICE_TTL,
+            |w| {
+                for entry in service.txt.iter().copied().chain(extra) {
+                    w.label(entry);
+                }
+            },
+        );
+    }
+
+    fn record(
+        &mut self,
+        name: &[&str],
+        rtype: u16,
+        class: u16,
+        ttl: u32,
+        rdata: impl FnOnce(&mut Self),
+    ) {
+        self.name(name);
+        self.u16(rtype);
+        self.u16(class);
+        self.u32(ttl);
+
+        let len_at = self.len;
+        self.u16(0); // placeholder for the rdata length
+        let start = self.len;
+        rdata(self);
+        let rdata_len = (self.len - start) as u16;
+        if let Some(len) = self.buf.get_mut(len_at..len_at + 2) {
+            len.copy_from_slice(&rdata_len.to_be_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use simple_dns::rdata::RData;
+    use simple_dns::{Name, Packet, PacketFlag, Question, CLASS, TYPE};
+
+    use super::*;
+
+    fn query(name: &str, qtype: TYPE) -> std::vec::Vec<u8> {
+        let mut packet = Packet::new_query(0);
+        let name = Name::new_unchecked(name);
+        let question = Question::new(name, qtype.into(), CLASS::IN.into(), false);
+        packet.questions.push(question);
+        packet.build_bytes_vec().unwrap()
+    }
+
+    fn collector() -> Collector {
+        Collector {
+            host: String::try_from("collector.lan").unwrap(),
+            port: 1234,
+            udp_port: None,
+        }
+    }
+
+    fn announced(buf: &mut [u8], http_port: Option<u16>) -> Packet<'_> {
+        let len = announcement(buf, "node", [192, 168, 1, 6], &collector(), http_port);
+        Packet::parse(&buf[..len]).unwrap()
+    }
+
+    fn srv_port(packet: &Packet, instance: &str) -> Option<u16> {
+        packet
+            .answers
+            .iter()
+            .find_map(|record| match &record.rdata {
+                RData::SRV(srv) if record.name == Name::new_unchecked(instance) => {
+                    assert_eq!(srv.target, Name::new_unchecked("node.local"));
+                    Some(srv.port)
+                }
+                _ => None,
+            })
+    }
+
+    fn pointers(packet: &Packet, from: &str) -> std::vec::Vec<String> {
+        packet
+            .answers
+            .iter()
+            .filter(|record| record.name == Name::new_unchecked(from))
+            .filter_map(|record| match &record.rdata {
+                RData::PTR(ptr) => Some(ptr.0.to_string()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn authoritative_response_with_our_address() {
+        let mut buf = [0; 512];
+        let packet = announced(&mut buf, None);
+        assert!(packet.has_flags(PacketFlag::RESPONSE | PacketFlag::AUTHORITATIVE_ANSWER));
+        assert!(packet.questions.is_empty());
+
+        let a = packet
+            .answers
+            .iter()
+            .find_map(|record| match &record.rdata {
+                RData::A(a) => Some((record.name.to_string(), a.address, record.cache_flush)),
+                _ => None,
+            });
+        let address = u32::from_be_bytes([192, 168, 1, 6]);
+        assert_eq!(a, Some(("node.local".to_string(), address, true)));
+    }
+
+    #[test]
+    fn sensor_service_without_http() {
+        let mut buf = [0; 512];
+        let packet = announced(&mut buf, None);
+        assert_eq!(packet.answers.len(), 5);
+
+        let services = pointers(&packet, "_services._dns-sd._udp.local");
+        assert_eq!(services, ["_envsensor._tcp.local"]);
+        let instances = pointers(&packet, "_envsensor._tcp.local");
+        assert_eq!(instances, ["node._envsensor._tcp.local"]);
+        assert_eq!(srv_port(&packet, "node._envsensor._tcp.local"), Some(0));
+
+        let txt = packet
+            .answers
+            .iter()
+            .find_map(|record| match &record.rdata {
+                RData::TXT(txt) => Some(txt.attributes()),
+                _ => None,
+            })
+            .unwrap();
+        let entry = |key: &str| txt.get(key).cloned().flatten();
+        assert_eq!(entry("protocol").as_deref(), Some("large_bedroom"));
+        assert_eq!(entry("collector").as_deref(), Some("collector.lan:1234"));
+    }
+
+    #[test]
+    fn both_services_with_http() {
+        let mut buf = [0; 512];
+        let packet = announced(&mut buf, Some(8080));
+        assert_eq!(packet.answers.len(), 9);
+
+        let services = pointers(&packet, "_services._dns-sd._udp.local");
+        assert_eq!(services, ["_envsensor._tcp.local", "_http._tcp.local"]);
+        assert_eq!(srv_port(&packet, "node._envsensor._tcp.local"), Some(0));
+        assert_eq!(srv_port(&packet, "node._http._tcp.local"), Some(8080));
+    }
+
+    #[test]
+    fn answers_questions_about_us() {
+        let asks = |name, qtype, serve_http| asks_for_us(&query(name, qtype), "node", serve_http);
+        assert!(asks("node.local", TYPE::A, false));
+        assert!(asks("NODE.local", TYPE::A, false));
+        assert!(!asks("other.local", TYPE::A, false));
+
+        assert!(asks("_envsensor._tcp.local", TYPE::PTR, false));
+        assert!(asks("_services._dns-sd._udp.local", TYPE::PTR, false));
+        assert!(asks("node._envsensor._tcp.local", TYPE::SRV, false));
+        assert!(asks("node._envsensor._tcp.local", TYPE::TXT, false));
+        assert!(!asks("other._envsensor._tcp.local", TYPE::SRV, false));
+
+        assert!(!asks("_http._tcp.local", TYPE::PTR, false));
+        assert!(asks("_http._tcp.local", TYPE::PTR, true));
+        assert!(asks("node._http._tcp.local", TYPE::SRV, true));
+    }
+
+    #[test]
+    fn ignores_responses() {
+        let mut buf = [0; 512];
+        let len = announcement(&mut buf, "node", [192, 168, 1, 6], &collector(), Some(80));
+        assert!(!asks_for_us(&buf[..len], "node", true));
+    }
+
+    #[test]
+    fn pointer_loop_is_rejected() {
+        let mut packet = query("node.local", TYPE::A);
+        // the name now points at itself
+        packet[12] = 0xC0;
+        packet[13] = 12;
+        assert!(!asks_for_us(&packet, "node", false));
+    }
+}
@@ -0,0 +1,758 @@
+//! Publishes every quantity to its own topic on an mqtt (3.1.1) broker,
+//! an alternative to the collector. The broker marks us offline through
+//! our last will when the connection drops. Packet building and parsing
+//! only touch byte slices, they are tested on the host.
+
+use core::cell::{Cell, RefCell};
+use core::fmt::Write as _;
+
+use defmt::{info, unwrap, warn};
+use embassy_futures::select::{self, Either4};
+use embassy_net::driver::Driver;
+use embassy_net::tcp::{self, TcpReader, TcpSocket, TcpWriter};
+use embassy_net::Stack;
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use embedded_io_async::{Read, ReadExactError, Write};
+use heapless::{Deque, String, Vec};
+use protocol::large_bedroom::Device;
+use rand::rngs::SmallRng;
+
+use super::backoff::Backoff;
+use super::Connection;
+use crate::channel::{Channel, PriorityValue};
+use crate::config::{self, Broker, Credentials, NodeConfig};
+use crate::latest::quantity;
+use crate::sensors::buttons::ButtonEvent;
+use crate::sensors::health::Registry;
+use crate::status::Status;
+
+mod discovery;
+
+use discovery::Announcer;
+
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+const CONNACK_TIMEOUT: Duration = Duration::from_secs(5);
+/// Large enough for any topic and payload we send
+const MAX_PACKET: usize = 160;
+const MAX_TOPIC: usize = 64;
+const MAX_PAYLOAD: usize = 64;
+const WILL_PAYLOAD: &[u8] = b"offline";
+/// Large enough for the connect packet with the longest client id,
+/// will and credentials the config allows. Strings are prefixed by
+/// their length.
+const MAX_CONNECT: usize = HEADER_ROOM
+    + 2 + 4 // protocol name
+    + 1 + 1 + 2 // level, flags and keep alive
+    + 2 + config::MAX_HOSTNAME
+    + 2 + MAX_TOPIC
+    + 2 + WILL_PAYLOAD.len()
+    + 2 + config::MAX_USERNAME
+    + 2 + config::MAX_PASSWORD;
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const PINGREQ: [u8; 2] = [0xC0, 0];
+const PINGRESP: u8 = 0xD0;
+/// Marks a publish packet that is send again
+const DUP: u8 = 0x08;
+/// Room for the fixed header: packet type and up to 4 length bytes
+const HEADER_ROOM: usize = 5;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum QoS {
+    AtMostOnce = 0,
+    AtLeastOnce = 1,
+}
+
+#[derive(defmt::Format)]
+enum SessionError {
+    Tcp(tcp::Error),
+    Closed,
+    Timeout,
+    /// The connack return code, 4 and 5 mean our credentials are wrong
+    Refused(u8),
+    Malformed,
+    TooLarge,
+}
+
+/// The packet does not fit the buffer
+#[derive(Debug, defmt::Format)]
+pub struct TooLarge;
+
+pub async fn send_published(
+    stack: &Stack<impl Driver>,
+    config: &NodeConfig,
+    broker: &Broker,
+    publish: &Channel,
+    health: &Registry,
+    connection: &Connection,
+    rng: SmallRng,
+) {
+    let mut rx_buffer = [0; 64];
+    let mut tx_buffer = [0; MAX_PACKET * 4];
+    let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+    socket.set_timeout(Some(KEEP_ALIVE + KEEP_ALIVE / 2));
+    let mut backoff = Backoff::new(rng);
+
+    let in_flight = InFlight::new();
+    let availability = topic(&broker.topic_prefix, "availability");
+    let mut connect = [0; MAX_CONNECT];
+
+    let announcer = broker.discovery_prefix.as_ref().map(|prefix| Announcer {
+        discovery_prefix: prefix,
+        hostname: &config.hostname,
+        topic_prefix: &broker.topic_prefix,
+    });
+    loop {
+        let down_since = Instant::now();
+        let attempts = super::connect(
+            stack,
+            &mut socket,
+            (&broker.host, broker.port),
+            publish,
+            &mut backoff,
+            None,
+        )
+        .await;
+
+        let session = match connect_packet(
+            &mut connect,
+            &config.hostname,
+            KEEP_ALIVE,
+            (&availability, WILL_PAYLOAD),
+            broker.credentials.as_ref(),
+        ) {
+            Ok(len) => open_session(&mut socket, &connect[..len]).await,
+            Err(TooLarge) => Err(SessionError::TooLarge),
+        };
+        if let Err(e) = session {
+            warn!("could not open mqtt session: {}", e);
+            super::drop_connection(&mut socket, connection);
+            Timer::after(backoff.next()).await;
+            continue;
+        }
+
+        info!("connected to mqtt broker");
+        connection.set_connected(true);
+        // prevent out-dated data from being send
+        publish.clear().await;
+        publish.send_status(Status::Reconnected {
+            attempts,
+            disconnected_secs: down_since.elapsed().as_secs() as u32,
+            lost_messages: in_flight.take_lost(),
+        });
+        backoff.reset();
+
+        let (mut reader, mut writer) = socket.split();
+        let receive = handle_received(&mut reader, &in_flight);
+        let send = send_messages(
+            &mut writer,
+            &broker.topic_prefix,
+            &availability,
+            publish,
+            health,
+            &in_flight,
+            announcer.as_ref(),
+        );
+        select::select(receive, send).await;
+        super::drop_connection(&mut socket, connection);
+    }
+}
+
+async fn open_session(socket: &mut TcpSocket<'_>, connect: &[u8]) -> Result<(), SessionError> {
+    socket.write_all(connect).await.map_err(SessionError::Tcp)?;
+
+    let mut connack = [0; 4];
+    with_timeout(CONNACK_TIMEOUT, socket.read_exact(&mut connack))
+        .await
+        .map_err(|_| SessionError::Timeout)?
+        .map_err(|e| match e {
+            ReadExactError::UnexpectedEof => SessionError::Closed,
+            ReadExactError::Other(e) => SessionError::Tcp(e),
+        })?;
+
+    match parse(&connack) {
+        Some((Incoming::ConnAck { code: 0 }, _)) => Ok(()),
+        Some((Incoming::ConnAck { code }, _)) => Err(SessionError::Refused(code)),
+        _ => Err(SessionError::Malformed),
+    }
+}
+
+/// Returns on the first write error
+async fn send_messages(
+    writer: &mut TcpWriter<'_>,
+    prefix: &str,
+    availability: &str,
+    publish: &Channel,
+    health: &Registry,
+    in_flight: &InFlight,
+    announcer: Option<&Announcer<'_>>,
+) {
+    let mut packet = [0; MAX_PACKET];
+    let mut payload = [0; MAX_PAYLOAD];
+
+    let mut n = 0;
+    while let Some(mut unacked) = in_flight.unacked(n) {
+        unacked[0] |= DUP;
+        if let Err(e) = writer.write_all(&unacked).await {
+            warn!("write error: {:?}", e);
+            return;
+        }
+        n += 1;
+    }
+
+    let online = in_flight.encode(&mut packet, availability, b"online", QoS::AtLeastOnce, true);
+    let online = unwrap!(online, "availability topic and payload are bounded");
+    if let Err(e) = writer.write_all(online).await {
+        warn!("write error: {:?}", e);
+        return;
+    }
+
+    // sensors announced on this connection
+    let mut announced = Vec::new();
+
+    loop {
+        if let Some(announcer) = announcer {
+            let res = announce_working(writer, announcer, health, &mut announced).await;
+            if let Err(e) = res {
+                warn!("write error: {:?}", e);
+                return;
+            }
+        }
+
+        if let Some(event) = publish.next_button() {
+            let (name, len) = match &event {
+                ButtonEvent::Gesture { button, gesture } => (
+                    button.name(),
+                    serde_json_core::to_slice(gesture, &mut payload),
+                ),
+                ButtonEvent::Chord(buttons) => {
+                    ("chord", serde_json_core::to_slice(buttons, &mut payload))
+                }
+            };
+            let Ok(len) = len else {
+                warn!("button event does not fit mqtt payload: {}", event);
+                continue;
+            };
+            let mut button_topic = topic(prefix, "button/");
+            let _ignore_too_long = button_topic.push_str(name);
+            let Ok(to_send) = in_flight.encode(
+                &mut packet,
+                &button_topic,
+                &payload[..len],
+                QoS::AtLeastOnce,
+                false,
+            ) else {
+                warn!("button event does not fit mqtt packet: {}", event);
+                continue;
+            };
+            if let Err(e) = writer.write_all(to_send).await {
+                warn!("write error: {:?}", e);
+                return;
+            }
+            continue;
+        }
+
+        if let Some(status) = publish.next_status() {
+            let Ok(len) = serde_json_core::to_slice(&status, &mut payload) else {
+                warn!("status does not fit mqtt payload: {}", status);
+                continue;
+            };
+            let status_topic = topic(prefix, "node_status");
+            let Ok(to_send) = in_flight.encode(
+                &mut packet,
+                &status_topic,
+                &payload[..len],
+                QoS::AtMostOnce,
+                false,
+            ) else {
+                warn!("status does not fit mqtt packet: {}", status);
+                continue;
+            };
+            if let Err(e) = writer.write_all(to_send).await {
+                warn!("write error: {:?}", e);
+                return;
+            }
+            continue;
+        }
+
+        // the broker drops us if we are silent for longer then the keep alive
+        let keep_alive = Timer::after(KEEP_ALIVE / 2);
+        let next = select::select4(
+            publish.receive(),
+            keep_alive,
+            publish.button_ready(),
+            health.changed(),
+        );
+        let next = match next.await {
+            Either4::First(next) => next,
+            Either4::Second(()) => {
+                if let Err(e) = writer.write_all(&PINGREQ).await {
+                    warn!("write error: {:?}", e);
+                    return;
+                }
+                continue;
+            }
+            Either4::Third(()) | Either4::Fourth(()) => continue,
+        };
+
+        let Some((name, len)) = quantity(&next.value, &mut payload) else {
+            continue;
+        };
+        let quantity_topic = topic(prefix, name);
+        let qos = qos_for(&next);
+        let encoded = in_flight.encode(&mut packet, &quantity_topic, &payload[..len], qos, false);
+        let Ok(to_send) = encoded else {
+            warn!("{} does not fit mqtt packet", name);
+            continue;
+        };
+        if let Err(e) = writer.write_all(to_send).await {
+            warn!("write error: {:?}", e);
+            return;
+        }
+    }
+}
+
+/// Announces the sensors that currently have a working driver, skips
+/// those in `announced`
+async fn announce_working(
+    writer: &mut TcpWriter<'_>,
+    announcer: &Announcer<'_>,
+    health: &Registry,
+    announced: &mut Vec<Device, 5>,
+) -> Result<(), tcp::Error> {
+    for device in health.working() {
+        if !announced.contains(&device) {
+            announcer.announce(writer, &device).await?;
+            let _ignore_full = announced.push(device);
+        }
+    }
+    Ok(())
+}
+
+/// Returns once the connection closes or breaks
+async fn handle_received(reader: &mut TcpReader<'_>, in_flight: &InFlight) {
+    let mut buf = [0; 64];
+    let mut filled = 0;
+
+    loop {
+        if filled == buf.len() {
+            warn!("unexpectedly large packet from mqtt broker, dropping it");
+            filled = 0;
+        }
+
+        match reader.read(&mut buf[filled..]).await {
+            Ok(0) => {
+                warn!("mqtt broker closed the connection");
+                return;
+            }
+            Ok(n) => filled += n,
+            Err(e) => {
+                warn!("read error: {:?}", e);
+                return;
+            }
+        }
+
+        while let Some((packet, len)) = parse(&buf[..filled]) {
+            match packet {
+                Incoming::PubAck { id } => in_flight.ack(id),
+                Incoming::PingResp | Incoming::ConnAck { .. } | Incoming::Other => (),
+            }
+            buf.copy_within(len..filled, 0);
+            filled -= len;
+        }
+    }
+}
+
+/// Routine readings can get lost, a newer one follows soon
+fn qos_for(value: &PriorityValue) -> QoS {
+    if value.low_priority() && !value.must_arrive() {
+        QoS::AtMostOnce
+    } else {
+        QoS::AtLeastOnce
+    }
+}
+
+fn topic(prefix: &str, name: &str) -> String<MAX_TOPIC> {
+    let mut topic = String::new();
+    unwrap!(write!(topic, "{prefix}/{name}"));
+    topic
+}
+
+/// Hands out packet ids and keeps QoS 1 messages until the broker
+/// acknowledges them, they are send again on the next connection.
+struct InFlight {
+    next_id: Cell<u16>,
+    unacked: RefCell<Deque<(u16, Vec<u8, MAX_PACKET>), 4>>,
+    lost: Cell<u32>,
+}
+
+impl InFlight {
+    fn new() -> Self {
+        Self {
+            next_id: Cell::new(1),
+            unacked: RefCell::new(Deque::new()),
+            lost: Cell::new(0),
+        }
+    }
+
+    fn encode<'b>(
+        &self,
+        buf: &'b mut [u8],
+        topic: &str,
+        payload: &[u8],
+        qos: QoS,
+        retain: bool,
+    ) -> Result<&'b [u8], TooLarge> {
+        if qos == QoS::AtMostOnce {
+            let len = publish_packet(buf, topic, payload, qos, retain, 0)?;
+            return Ok(&buf[..len]);
+        }
+
+        let id = self.next_id.get();
+        let len = publish_packet(buf, topic, payload, qos, retain, id)?;
+        // zero is not a valid packet id
+        self.next_id.set(id.checked_add(1).unwrap_or(1));
+
+        let mut unacked = self.unacked.borrow_mut();
+        if unacked.is_full() {
+            warn!("too many unacknowledged mqtt messages, dropping oldest");
+            unacked.pop_front();
+            self.lost.set(self.lost.get() + 1);
+        }
+        let copy = unwrap!(Vec::from_slice(&buf[..len]));
+        let _cant_be_full = unacked.push_back((id, copy));
+        Ok(&buf[..len])
+    }
+
+    fn ack(&self, id: u16) {
+        let mut unacked = self.unacked.borrow_mut();
+        for _ in 0..unacked.len() {
+            let Some(entry) = unacked.pop_front() else {
+                break;
+            };
+            if entry.0 != id {
+                let _cant_be_full = unacked.push_back(entry);
+            }
+        }
+    }
+
+    /// Copy of the nth unacknowledged packet, oldest first
+    fn unacked(&self, n: usize) -> Option<Vec<u8, MAX_PACKET>> {
+        self.unacked
+            .borrow()
+            .iter()
+            .nth(n)
+            .map(|(_, packet)| packet.clone())
+    }
+
+    /// Messages dropped before the broker acknowledged them since
+    /// the last call
+    fn take_lost(&self) -> u32 {
+        self.lost.take()
+    }
+}
+
+/// Returns the length. The will is published retained by the broker
+/// when we disappear without disconnecting.
+pub fn connect_packet(
+    buf: &mut [u8],
+    client_id: &str,
+    keep_alive: Duration,
+    (will_topic, will_payload): (&str, &[u8]),
+    credentials: Option<&Credentials>,
+) -> Result<usize, TooLarge> {
+    const CLEAN_SESSION: u8 = 0x02;
+    const WILL: u8 = 0x04;
+    const WILL_RETAIN: u8 = 0x20;
+    const PASSWORD: u8 = 0x40;
+    const USERNAME: u8 = 0x80;
+
+    let mut flags = CLEAN_SESSION | WILL | WILL_RETAIN;
+    if credentials.is_some() {
+        flags |= USERNAME | PASSWORD;
+    }
+
+    let mut w = Writer::new(buf);
+    w.string(b"MQTT");
+    w.bytes(&[4]); // protocol level, 3.1.1
+    w.bytes(&[flags]);
+    w.u16(keep_alive.as_secs() as u16);
+
+    w.string(client_id.as_bytes());
+    w.string(will_topic.as_bytes());
+    w.string(will_payload);
+    if let Some(Credentials { username, password }) = credentials {
+        w.string(username.as_bytes());
+        w.string(password.as_bytes());
+    }
+    w.finish(CONNECT)
+}
+
+/// Returns the length. The packet id is only used with QoS 1.
+pub fn publish_packet(
+    buf: &mut [u8],
+    topic: &str,
+    payload: &[u8],
+    qos: QoS,
+    retain: bool,
+    id: u16,
+) -> Result<usize, TooLarge> {
+    let mut w = Writer::new(buf);
+    w.string(topic.as_bytes());
+    if qos == QoS::AtLeastOnce {
+        w.u16(id);
+    }
+    w.bytes(payload);
+    w.finish(PUBLISH | (qos as u8) << 1 | retain as u8)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Incoming {
+    ConnAck {
+        code: u8,
+    },
+    PubAck {
+        id: u16,
+    },
+    PingResp,
+    /// Anything we do not act on
+    Other,
+}
+
+/// Parses the packet at the start of `buf`. Returns it and its length,
+/// or None if it is not complete yet.
+pub fn parse(buf: &[u8]) -> Option<(Incoming, usize)> {
+    let first = *buf.first()?;
+
+    let mut remaining = 0;
+    let mut pos = 1;
+    for shift in [0, 7, 14, 21] {
+        let byte = *buf.get(pos)?;
+        pos += 1;
+        remaining |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+
+    let body = buf.get(pos..pos + remaining)?;
+    let packet = match (first & 0xF0, body) {
+        (CONNACK, [_flags, code]) => Incoming::ConnAck { code: *code },
+        (PUBACK, [high, low]) => Incoming::PubAck {
+            id: u16::from_be_bytes([*high, *low]),
+        },
+        (PINGRESP, _) => Incoming::PingResp,
+        _ => Incoming::Other,
+    };
+    Some((packet, pos + remaining))
+}
+
+/// Writes the body of a packet leaving room for the fixed header, which
+/// is only known once the body length is. Once something did not fit
+/// the packet can only be finished as [`TooLarge`].
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    too_large: bool,
+}
+
+impl<'a> Writer<'a> {
+    fn new(buf: &'a mut [u8]) -> Self {
+        Self {
+            buf,
+            len: HEADER_ROOM,
+            too_large: false,
+        }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        let Some(dest) = self.buf.get_mut(self.len..self.len + bytes.len()) else {
+            self.too_large = true;
+            return;
+        };
+        dest.copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+
+    fn u16(&mut self, val: u16) {
+        self.bytes(&val.to_be_bytes())
+    }
+
+    /// Length prefixed
+    fn string(&mut self, string: &[u8]) {
+        self.u16(string.len() as u16);
+        self.bytes(string);
+    }
+
+    /// Moves the packet to the start of the buffer, returns its length
+    fn finish(self, first_byte: u8) -> Result<usize, TooLarge> {
+        if self.too_large {
+            return Err(TooLarge);
+        }
+
+        let mut header = Vec::<u8, HEADER_ROOM>::new();
+        let _cant_be_full = header.push(first_byte);
+        let mut remaining = self.len - HEADER_ROOM;
+        loop {
+            let mut byte = (remaining % 128) as u8;
+            remaining /= 128;
+            if remaining > 0 {
+                byte |= 0x80;
+            }
+            let _cant_be_full = header.push(byte);
+            if remaining == 0 {
+                break;
+            }
+        }
+
+        let start = HEADER_ROOM - header.len();
+        self.buf[start..HEADER_ROOM].copy_from_slice(&header);
+        self.buf.copy_within(start..self.len, 0);
+        Ok(self.len - start)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+    use protocol::large_bedroom::{BedButton, Error, LargeBedroom as LB};
+    use protocol::Press;
+
+    use super::*;
+
+    /// Length prefixed, as the packets encode strings
+    fn string(out: &mut std::vec::Vec<u8>, s: &[u8]) {
+        out.extend_from_slice(&(s.len() as u16).to_be_bytes());
+        out.extend_from_slice(s);
+    }
+
+    fn connect_body(flags: u8) -> std::vec::Vec<u8> {
+        let mut body = std::vec::Vec::new();
+        string(&mut body, b"MQTT");
+        body.extend_from_slice(&[4, flags, 0, 60]);
+        string(&mut body, b"node");
+        string(&mut body, b"bed/availability");
+        string(&mut body, b"offline");
+        body
+    }
+
+    fn connect(credentials: Option<&Credentials>) -> std::vec::Vec<u8> {
+        let mut buf = [0; MAX_CONNECT];
+        let will = ("bed/availability", WILL_PAYLOAD);
+        let len = connect_packet(&mut buf, "node", KEEP_ALIVE, will, credentials).unwrap();
+        buf[..len].to_vec()
+    }
+
+    #[test]
+    fn connect_with_retained_will() {
+        let body = connect_body(0x26);
+        let mut expected = vec![CONNECT, body.len() as u8];
+        expected.extend_from_slice(&body);
+        assert_eq!(connect(None), expected);
+    }
+
+    #[test]
+    fn connect_with_credentials() {
+        let credentials = Credentials {
+            username: String::try_from("user").unwrap(),
+            password: String::try_from("secret").unwrap(),
+        };
+        let mut body = connect_body(0xE6);
+        string(&mut body, b"user");
+        string(&mut body, b"secret");
+        let mut expected = vec![CONNECT, body.len() as u8];
+        expected.extend_from_slice(&body);
+        assert_eq!(connect(Some(&credentials)), expected);
+    }
+
+    #[test]
+    fn longest_connect_fits() {
+        let credentials = Credentials {
+            username: core::iter::repeat('u').take(config::MAX_USERNAME).collect(),
+            password: core::iter::repeat('p').take(config::MAX_PASSWORD).collect(),
+        };
+        let client_id: String<{ config::MAX_HOSTNAME }> =
+            core::iter::repeat('n').take(config::MAX_HOSTNAME).collect();
+        let will_topic: String<MAX_TOPIC> = core::iter::repeat('t').take(MAX_TOPIC).collect();
+
+        let mut buf = [0; MAX_CONNECT];
+        let will = (will_topic.as_str(), WILL_PAYLOAD);
+        let credentials = Some(&credentials);
+        let res = connect_packet(&mut buf, &client_id, KEEP_ALIVE, will, credentials);
+        assert!(res.is_ok());
+        let short = &mut buf[..MAX_CONNECT - 1];
+        let res = connect_packet(short, &client_id, KEEP_ALIVE, will, credentials);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn publish_at_most_once() {
+        let mut buf = [0; MAX_PACKET];
+        let len = publish_packet(&mut buf, "t/x", b"1", QoS::AtMostOnce, false, 7).unwrap();
+        assert_eq!(buf[..len], [PUBLISH, 6, 0, 3, b't', b'/', b'x', b'1']);
+    }
+
+    #[test]
+    fn publish_at_least_once_retained() {
+        let mut buf = [0; MAX_PACKET];
+        let len = publish_packet(&mut buf, "t", b"on", QoS::AtLeastOnce, true, 0x1234).unwrap();
+        let first = PUBLISH | 0x02 | 0x01;
+        assert_eq!(buf[..len], [first, 7, 0, 1, b't', 0x12, 0x34, b'o', b'n']);
+    }
+
+    #[test]
+    fn remaining_length_takes_two_bytes_from_128() {
+        let mut buf = [0; 256];
+        let payload = [b'a'; 200];
+        let len = publish_packet(&mut buf, "t", &payload, QoS::AtMostOnce, false, 0).unwrap();
+        // 3 bytes of topic and 200 of payload: 203 = 0x4B + 1 * 128
+        assert_eq!(buf[..5], [PUBLISH, 0xCB, 0x01, 0, 1]);
+        assert_eq!(len, 3 + 203);
+        assert_eq!(parse(&buf[..len]), Some((Incoming::Other, len)));
+    }
+
+    #[test]
+    fn publish_too_large() {
+        let mut buf = [0; 8];
+        let res = publish_packet(&mut buf, "t/x", b"12", QoS::AtMostOnce, false, 0);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn parses_acknowledgements() {
+        let connack = [CONNACK, 2, 0, 5];
+        assert_eq!(parse(&connack), Some((Incoming::ConnAck { code: 5 }, 4)));
+
+        let two = [PUBACK, 2, 0x12, 0x34, PINGRESP, 0];
+        assert_eq!(parse(&two), Some((Incoming::PubAck { id: 0x1234 }, 4)));
+        assert_eq!(parse(&two[4..]), Some((Incoming::PingResp, 2)));
+    }
+
+    #[test]
+    fn waits_for_the_rest_of_a_packet() {
+        assert_eq!(parse(&[]), None);
+        assert_eq!(parse(&[PUBACK]), None);
+        assert_eq!(parse(&[PUBACK, 2, 0x12]), None);
+        assert_eq!(parse(&[PUBLISH, 0x80]), None);
+    }
+
+    #[test]
+    fn qos_from_priority() {
+        let publish = Channel::new();
+        publish.send_p0(LB::Temperature(20.0));
+        publish.send_p2(LB::BedButton(BedButton::TopLeft(Press(100))));
+        publish.send_error(Error::Timeout(Device::Sht31));
+        block_on(publish.send_critical_error(Error::Timeout(Device::Mhz14)));
+
+        let mut qos = std::vec::Vec::new();
+        while let Some(next) = publish.next_ready() {
+            qos.push(qos_for(&next));
+        }
+        // highest priority first: critical error, button, the rest
+        assert_eq!(qos[..2], [QoS::AtLeastOnce, QoS::AtLeastOnce]);
+        assert_eq!(qos[2..], [QoS::AtMostOnce, QoS::AtMostOnce]);
+    }
+}
@@ -0,0 +1,158 @@
+//! Home assistant mqtt discovery. Every quantity of a sensor with a
+//! working driver is announced as a sensor entity, grouped under one
+//! device per node. Which sensors work comes from their supervisors.
+//! The configs are retained so home assistant picks them up after a
+//! restart, we announce again on every connection in case the broker
+//! lost them.
+
+use core::fmt::Write as _;
+
+use defmt::{unwrap, warn};
+use embassy_net::tcp::{self, TcpWriter};
+use embedded_io_async::Write;
+use heapless::String;
+use protocol::large_bedroom::Device;
+use serde::Serialize;
+
+use super::QoS;
+
+/// Large enough for the config of any quantity
+const MAX_CONFIG: usize = 512;
+
+struct Quantity {
+    device: Device,
+    /// Must match the topic name in [`crate::latest::quantity`]
+    name: &'static str,
+    label: &'static str,
+    device_class: Option<&'static str>,
+    unit: Option<&'static str>,
+}
+
+const fn quantity(
+    device: Device,
+    name: &'static str,
+    label: &'static str,
+    device_class: Option<&'static str>,
+    unit: Option<&'static str>,
+) -> Quantity {
+    Quantity {
+        device,
+        name,
+        label,
+        device_class,
+        unit,
+    }
+}
+
+const MASS: Option<&str> = Some("µg/m³");
+const NUMBER: Option<&str> = Some("#/cm³");
+
+#[rustfmt::skip]
+const QUANTITIES: [Quantity; 16] = [
+    quantity(Device::Sht31, "temperature", "Temperature", Some("temperature"), Some("°C")),
+    quantity(Device::Sht31, "humidity", "Humidity", Some("humidity"), Some("%")),
+    quantity(Device::Bme680, "pressure", "Pressure", Some("atmospheric_pressure"), Some("hPa")),
+    quantity(Device::Bme680, "gas_resistance", "Gas resistance", None, Some("Ω")),
+    quantity(Device::Mhz14, "co2", "CO2", Some("carbon_dioxide"), Some("ppm")),
+    quantity(Device::Sps30, "mass_pm0_5", "PM0.5 mass", None, MASS),
+    quantity(Device::Sps30, "mass_pm1_0", "PM1 mass", Some("pm1"), MASS),
+    quantity(Device::Sps30, "mass_pm2_5", "PM2.5 mass", Some("pm25"), MASS),
+    quantity(Device::Sps30, "mass_pm4_0", "PM4 mass", None, MASS),
+    quantity(Device::Sps30, "mass_pm10", "PM10 mass", Some("pm10"), MASS),
+    quantity(Device::Sps30, "number_pm1_0", "PM1 count", None, NUMBER),
+    quantity(Device::Sps30, "number_pm2_5", "PM2.5 count", None, NUMBER),
+    quantity(Device::Sps30, "number_pm4_0", "PM4 count", None, NUMBER),
+    quantity(Device::Sps30, "number_pm10", "PM10 count", None, NUMBER),
+    quantity(Device::Sps30, "typical_particle_size", "Typical particle size", None, Some("µm")),
+    quantity(Device::Max44, "brightness", "Brightness", Some("illuminance"), Some("lx")),
+];
+
+#[derive(Serialize)]
+struct Config<'a> {
+    name: &'a str,
+    unique_id: &'a str,
+    state_topic: &'a str,
+    availability_topic: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_class: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unit_of_measurement: Option<&'a str>,
+    state_class: &'a str,
+    device: DeviceInfo<'a>,
+}
+
+#[derive(Serialize)]
+struct DeviceInfo<'a> {
+    identifiers: [&'a str; 1],
+    name: &'a str,
+    model: &'a str,
+}
+
+pub struct Announcer<'a> {
+    /// Home assistant listens on `homeassistant` by default
+    pub discovery_prefix: &'a str,
+    pub hostname: &'a str,
+    pub topic_prefix: &'a str,
+}
+
+impl Announcer<'_> {
+    /// Publishes the config of every quantity `device` measures
+    pub async fn announce(
+        &self,
+        writer: &mut TcpWriter<'_>,
+        device: &Device,
+    ) -> Result<(), tcp::Error> {
+        let mut payload = [0; MAX_CONFIG];
+        let mut packet = [0; MAX_CONFIG + 128];
+
+        for quantity in QUANTITIES.iter().filter(|q| q.device == *device) {
+            let Some(len) = self.config(quantity, &mut payload) else {
+                warn!("discovery config for {} does not fit", quantity.name);
+                continue;
+            };
+
+            let mut topic = String::<128>::new();
+            unwrap!(write!(
+                topic,
+                "{}/sensor/{}/{}/config",
+                self.discovery_prefix, self.hostname, quantity.name
+            ));
+            let Ok(len) = super::publish_packet(
+                &mut packet,
+                &topic,
+                &payload[..len],
+                QoS::AtMostOnce,
+                true,
+                0,
+            ) else {
+                warn!("discovery packet for {} does not fit", quantity.name);
+                continue;
+            };
+            writer.write_all(&packet[..len]).await?;
+        }
+        Ok(())
+    }
+
+    fn config(&self, quantity: &Quantity, payload: &mut [u8]) -> Option<usize> {
+        let state_topic = super::topic(self.topic_prefix, quantity.name);
+        let availability_topic = super::topic(self.topic_prefix, "availability");
+        let mut unique_id = String::<64>::new();
+        write!(unique_id, "{}_{}", self.hostname, quantity.name).ok()?;
+
+        let config = Config {
+            name: quantity.label,
+            unique_id: &unique_id,
+            state_topic: &state_topic,
+            availability_topic: &availability_topic,
+            device_class: quantity.device_class,
+            unit_of_measurement: quantity.unit,
+            state_class: "measurement",
+            device: DeviceInfo {
+                identifiers: [self.hostname],
+                name: self.hostname,
+                model: "large bedroom sensor node",
+            },
+        };
+        serde_json_core::to_slice(&config, payload).ok()
+    }
+}
@@ -0,0 +1,163 @@
+//! Sets the clock from an ntp server using the simple (sntp) subset of
+//! the protocol. Good to within the network round trip, plenty for
+//! timestamping readings.
+
+use defmt::{debug, warn};
+use embassy_net::driver::Driver;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::Stack;
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+
+use crate::channel::Channel;
+
+const PORT: u16 = 123;
+const RESYNC: Duration = Duration::from_secs(60 * 60);
+const RETRY: Duration = Duration::from_secs(60);
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// Seconds between the ntp epoch (1900) and the unix epoch (1970)
+const NTP_TO_UNIX: u64 = 2_208_988_800;
+
+pub async fn keep_clock_set(stack: &Stack<impl Driver>, server: &str, publish: &Channel) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0; 64];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0; 64];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(0) {
+        warn!("could not bind sntp socket: {:?}", e);
+        return;
+    }
+
+    loop {
+        match sync(stack, &socket, server).await {
+            Some(unix_ms) => {
+                publish.clock().set(unix_ms);
+                Timer::after(RESYNC).await;
+            }
+            None => Timer::after(RETRY).await,
+        }
+    }
+}
+
+/// Current unix time in milliseconds
+async fn sync(stack: &Stack<impl Driver>, socket: &UdpSocket<'_>, server: &str) -> Option<u64> {
+    let address = match super::resolve(stack, server).await {
+        Ok(address) => address,
+        Err(e) => {
+            warn!("could not resolve ntp server {}: {:?}", server, e);
+            return None;
+        }
+    };
+
+    let mut packet = [0; 48];
+    packet[0] = 0x23; // no leap second warning, version 4, client mode
+    let sent_at = Instant::now();
+    if let Err(e) = socket.send_to(&packet, (address, PORT)).await {
+        warn!("could not send sntp request: {:?}", e);
+        return None;
+    }
+
+    let (len, _) = match with_timeout(TIMEOUT, socket.recv_from(&mut packet)).await {
+        Ok(Ok(received)) => received,
+        Ok(Err(e)) => {
+            warn!("sntp receive error: {:?}", e);
+            return None;
+        }
+        Err(_timeout) => {
+            warn!("no answer from ntp server");
+            return None;
+        }
+    };
+    let round_trip = sent_at.elapsed();
+
+    let unix_ms = parse_transmit_time(&packet[..len])?;
+    debug!("sntp round trip took {}", round_trip);
+    // the server answered about halfway the round trip
+    Some(unix_ms + round_trip.as_millis() / 2)
+}
+
+/// The server's transmit timestamp in unix milliseconds, None if the
+/// packet is not a valid server reply.
+pub fn parse_transmit_time(packet: &[u8]) -> Option<u64> {
+    let packet: &[u8; 48] = packet.get(..48)?.try_into().ok()?;
+    let mode = packet[0] & 0x7;
+    let stratum = packet[1];
+    // stratum 0 is a kiss of death, the server wants us to back off
+    if mode != 4 || stratum == 0 {
+        return None;
+    }
+
+    let mut seconds = u32::from_be_bytes(packet[40..44].try_into().ok()?) as u64;
+    let fraction = u32::from_be_bytes(packet[44..48].try_into().ok()?) as u64;
+    // the seconds wrap in february 2036, small values are from after
+    // that (era 1) since we are well past 1968
+    if seconds < 1 << 31 {
+        seconds += 1 << 32;
+    }
+    let unix_seconds = seconds.checked_sub(NTP_TO_UNIX)?;
+    Some(unix_seconds * 1000 + ((fraction * 1000) >> 32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A server reply with `seconds` and `fraction` on the wire
+    fn reply(seconds: u32, fraction: u32) -> [u8; 48] {
+        let mut packet = [0; 48];
+        packet[0] = 0x24; // version 4, server mode
+        packet[1] = 2;
+        packet[40..44].copy_from_slice(&seconds.to_be_bytes());
+        packet[44..48].copy_from_slice(&fraction.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn before_the_rollover() {
+        // 2023-11-14 22:13:20 UTC
+        let seconds = (1_700_000_000 + NTP_TO_UNIX) as u32;
+        let packet = reply(seconds, 1 << 31);
+        assert_eq!(parse_transmit_time(&packet), Some(1_700_000_000_500));
+    }
+
+    #[test]
+    fn after_the_rollover() {
+        // 2036-07-18 13:20:00 UTC, past the wrap in february 2036
+        let unix = 2_100_000_000u64;
+        let seconds = (unix + NTP_TO_UNIX - (1 << 32)) as u32;
+        assert!(seconds < 1 << 31);
+        let packet = reply(seconds, 0);
+        assert_eq!(parse_transmit_time(&packet), Some(unix * 1000));
+    }
+
+    #[test]
+    fn longer_packets_are_fine() {
+        let mut packet = [0; 68]; // with a key id and digest
+        packet[..48].copy_from_slice(&reply(3_908_988_800, 0));
+        assert_eq!(parse_transmit_time(&packet), Some(1_700_000_000_000));
+    }
+
+    #[test]
+    fn rejects_short_packets() {
+        let packet = reply(3_908_988_800, 0);
+        assert_eq!(parse_transmit_time(&packet[..47]), None);
+        assert_eq!(parse_transmit_time(&[]), None);
+    }
+
+    #[test]
+    fn rejects_what_is_not_a_server_reply() {
+        let mut packet = reply(3_908_988_800, 0);
+        packet[0] = 0x23; // client mode
+        assert_eq!(parse_transmit_time(&packet), None);
+
+        let mut packet = reply(3_908_988_800, 0);
+        packet[1] = 0; // kiss of death
+        assert_eq!(parse_transmit_time(&packet), None);
+    }
+}
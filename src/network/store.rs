@@ -0,0 +1,195 @@
+use core::mem::{self, Discriminant};
+
+use embassy_time::{Duration, Instant};
+use heapless::{Deque, Vec};
+use protocol::large_bedroom::{Device, LargeBedroom};
+use protocol::Sensor;
+use serde::{Deserialize, Serialize};
+
+use crate::channel;
+use crate::clock::{Clock, SampleTime};
+
+/// Keep at most one reading per quantity per interval
+const DOWNSAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+/// About three hours of all quantities at one per minute
+const CAPACITY: usize = 200;
+
+/// Send in place of a stored reading
+#[derive(Serialize, Deserialize)]
+pub struct Replayed {
+    pub sampled_at: SampleTime,
+    pub value: Sensor,
+}
+
+/// What is downsampled together
+#[derive(PartialEq)]
+enum Kind {
+    Quantity(Discriminant<LargeBedroom>),
+    /// A sensor that keeps failing would otherwise fill the store
+    ErrorOf(Device),
+}
+
+/// Readings taken while the collector was unreachable. Bounded, once
+/// full the oldest readings are dropped. Only kept in RAM: the one
+/// flash sector the firmware leaves free holds the config, and erasing
+/// it for readings would risk losing the config on a power cut.
+pub struct Store {
+    readings: Deque<(Instant, Sensor), CAPACITY>,
+    last_stored: Vec<(Kind, Instant), 32>,
+}
+
+impl Store {
+    pub fn new() -> Self {
+        Self {
+            readings: Deque::new(),
+            last_stored: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, at: Instant, value: Sensor) {
+        let kind = if let Sensor::LargeBedroom(reading) = &value {
+            // acting on a button press hours later would be confusing
+            if let LargeBedroom::BedButton(_) = reading {
+                return;
+            }
+            Kind::Quantity(mem::discriminant(reading))
+        } else if let Sensor::LargeBedroomError(error) = &value {
+            Kind::ErrorOf(channel::device_of(error))
+        } else {
+            return;
+        };
+        if !self.should_sample(kind, at) {
+            return;
+        }
+
+        if self.readings.is_full() {
+            self.readings.pop_front();
+        }
+        let _cant_be_full = self.readings.push_back((at, value));
+    }
+
+    /// Oldest stored reading, call [`Store::pop`] once it has been sent
+    pub fn peek(&self, clock: &Clock) -> Option<Replayed> {
+        self.readings.front().map(|(at, value)| Replayed {
+            sampled_at: clock.sample_time(*at),
+            value: value.clone(),
+        })
+    }
+
+    pub fn pop(&mut self) {
+        self.readings.pop_front();
+    }
+
+    pub fn clear(&mut self) {
+        self.readings.clear();
+    }
+
+    /// `at` is when the reading was taken
+    fn should_sample(&mut self, kind: Kind, at: Instant) -> bool {
+        match self.last_stored.iter_mut().find(|(k, _)| *k == kind) {
+            Some((_, last)) if at.saturating_duration_since(*last) < DOWNSAMPLE_INTERVAL => false,
+            Some((_, last)) => {
+                *last = at;
+                true
+            }
+            None => {
+                let _ignore_full = self.last_stored.push((kind, at));
+                true
+            }
+        }
+    }
+}
+
+impl Default for Store {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use protocol::large_bedroom::{BedButton, Error};
+    use protocol::Press;
+
+    use super::*;
+
+    fn at(secs: u64) -> Instant {
+        Instant::from_secs(secs)
+    }
+
+    fn temperature(value: f32) -> Sensor {
+        Sensor::LargeBedroom(LargeBedroom::Temperature(value))
+    }
+
+    fn stored(store: &Store) -> usize {
+        store.readings.len()
+    }
+
+    #[test]
+    fn one_reading_per_quantity_per_interval() {
+        let mut store = Store::new();
+        store.push(at(0), temperature(20.0));
+        store.push(at(59), temperature(21.0));
+        store.push(at(0), Sensor::LargeBedroom(LargeBedroom::Humidity(50.0)));
+        assert_eq!(stored(&store), 2);
+
+        store.push(at(60), temperature(22.0));
+        assert_eq!(stored(&store), 3);
+    }
+
+    #[test]
+    fn errors_are_downsampled_per_device() {
+        let mut store = Store::new();
+        let timeout = |device| Sensor::LargeBedroomError(Error::Timeout(device));
+        store.push(at(0), timeout(Device::Sht31));
+        store.push(at(1), timeout(Device::Sht31));
+        store.push(at(1), timeout(Device::Mhz14));
+        assert_eq!(stored(&store), 2);
+
+        // a reading does not hold back the errors of its sensor
+        store.push(at(2), temperature(20.0));
+        assert_eq!(stored(&store), 3);
+    }
+
+    #[test]
+    fn button_presses_are_not_stored() {
+        let mut store = Store::new();
+        let press = BedButton::TopLeft(Press(100));
+        store.push(at(0), Sensor::LargeBedroom(LargeBedroom::BedButton(press)));
+        assert_eq!(stored(&store), 0);
+    }
+
+    #[test]
+    fn oldest_is_dropped_once_full() {
+        let mut store = Store::new();
+        for minute in 0..CAPACITY as u64 + 1 {
+            store.push(at(minute * 60), temperature(minute as f32));
+        }
+        assert_eq!(stored(&store), CAPACITY);
+
+        let clock = Clock::new();
+        let oldest = store.peek(&clock).map(|replay| replay.value);
+        assert!(matches!(
+            oldest,
+            Some(Sensor::LargeBedroom(LargeBedroom::Temperature(v))) if v == 1.0
+        ));
+    }
+
+    #[test]
+    fn replayed_oldest_first() {
+        let mut store = Store::new();
+        store.push(at(0), temperature(20.0));
+        store.push(at(60), temperature(21.0));
+
+        let clock = Clock::new();
+        let sampled_ms = |store: &Store| match store.peek(&clock).map(|r| r.sampled_at) {
+            Some(SampleTime::Uptime(ms)) => Some(ms),
+            _ => None,
+        };
+        assert_eq!(sampled_ms(&store), Some(0));
+        store.pop();
+        assert_eq!(sampled_ms(&store), Some(60_000));
+        store.pop();
+        assert!(store.peek(&clock).is_none());
+    }
+}
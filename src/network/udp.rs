@@ -0,0 +1,106 @@
+//! Sends the fast changing values, brightness, as udp datagrams. A lost
+//! datagram is replaced by the next one soon enough, waiting for a
+//! retransmit like tcp does only delays the newer values. Every datagram
+//! carries a sequence number so the collector can tell how many it lost.
+//! Like the frames in [`super::link`] the datagram carries the sample
+//! times the plain `SensorMessage` has no room for.
+
+use defmt::{unwrap, warn};
+use embassy_net::driver::Driver;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpAddress, Stack};
+use embassy_time::{Duration, Instant, Timer};
+use heapless::Vec;
+use protocol::Sensor;
+use serde::{Deserialize, Serialize};
+
+use super::Msg;
+use crate::channel::Channel;
+use crate::clock::SampleTime;
+
+/// Resolve again every so often, the collector might have moved
+const RESOLVE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const RETRY_RESOLVE: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize)]
+pub struct Datagram {
+    pub seq: u32,
+    pub readings: Msg,
+    /// One for every value in the message, in the same order
+    pub sampled_at: Vec<SampleTime, 6>,
+}
+
+impl Datagram {
+    pub const MAX_SIZE: usize = Msg::ENCODED_SIZE + 6 * SampleTime::MAX_SIZE + 8;
+}
+
+pub async fn send_stream(stack: &Stack<impl Driver>, host: &str, port: u16, publish: &Channel) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0; 16];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; Datagram::MAX_SIZE * 4];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    // any free port will do, we never receive
+    if let Err(e) = socket.bind(0) {
+        warn!("could not bind udp socket: {:?}", e);
+        return;
+    }
+
+    let mut datagram_buffer = [0; Datagram::MAX_SIZE];
+    let mut collector: Option<(IpAddress, Instant)> = None;
+    let mut seq = 0u32;
+
+    loop {
+        let address = match collector {
+            Some((address, at)) if at.elapsed() < RESOLVE_INTERVAL => address,
+            _ => match super::resolve(stack, host).await {
+                Ok(address) => {
+                    collector = Some((address, Instant::now()));
+                    address
+                }
+                Err(e) => {
+                    warn!("could not resolve {}: {:?}", host, e);
+                    Timer::after(RETRY_RESOLVE).await;
+                    continue;
+                }
+            },
+        };
+
+        let mut msg = Msg::new();
+        let mut sampled_at = Vec::new();
+        let mut next = Some(publish.receive_stream().await);
+        while let Some((value, at)) = next {
+            unwrap!(msg.values.push(Sensor::LargeBedroom(value)));
+            unwrap!(sampled_at.push(publish.clock().sample_time(at)));
+            if !msg.space_left() {
+                break;
+            }
+            next = publish.next_stream_ready();
+        }
+
+        let datagram = Datagram {
+            seq,
+            readings: msg,
+            sampled_at,
+        };
+        seq = seq.wrapping_add(1);
+        let datagram = match postcard::to_slice(&datagram, &mut datagram_buffer) {
+            Ok(datagram) => datagram,
+            Err(e) => {
+                warn!("datagram does not fit, dropping it: {}", e);
+                continue;
+            }
+        };
+        if let Err(e) = socket.send_to(datagram, (address, port)).await {
+            warn!("could not send datagram: {:?}", e);
+            // maybe there is no route anymore, resolve again
+            collector = None;
+        }
+    }
+}
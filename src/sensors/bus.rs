@@ -0,0 +1,341 @@
+//! Detects a stuck i2c bus and frees it. A device that lost track of
+//! the clock (for example because of noise on a long cable) can hold
+//! SDA low, after which every device on the bus fails. Clocking SCL
+//! until the device lets go and ending with a STOP condition frees the
+//! bus, the peripheral is reset since it will consider the bus busy.
+//!
+//! Counting and deciding when to recover works on any bus, only the
+//! recovery itself, [`recover`], needs the stm32.
+
+use core::cell::Cell;
+
+#[cfg(feature = "board")]
+use defmt::info;
+use defmt::warn;
+use embassy_embedded_hal::shared_bus::I2cDeviceError;
+#[cfg(feature = "board")]
+use embassy_stm32::pac::{self, gpio::vals};
+#[cfg(feature = "board")]
+use embassy_stm32::time::Hertz;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+use embedded_hal_async::i2c::{ErrorType, I2c, Operation};
+
+#[cfg(feature = "board")]
+use super::delay_us;
+#[cfg(feature = "board")]
+use super::init::I2cPeripheral;
+use crate::channel::Channel;
+use crate::status::{BusFault, Status};
+
+/// Extra slow, helps with longer cable runs
+#[cfg(feature = "board")]
+pub const FREQUENCY: Hertz = Hertz(150_000);
+/// Transactions on the bus, on any of the devices, that did not
+/// succeed in a row before we try to recover the bus
+const MAX_FAILED_IN_A_ROW: u8 = 8;
+/// Gives the sensors a chance to succeed before recovering again
+const MIN_BETWEEN_RECOVERIES: Duration = Duration::from_secs(10);
+/// Pins of the bus on GPIOB, must match the pins passed to `I2c::new`
+#[cfg(feature = "board")]
+const SCL: usize = 8;
+#[cfg(feature = "board")]
+const SDA: usize = 9;
+/// Half a period at 100 kHz in µs
+#[cfg(feature = "board")]
+const HALF_CLOCK: u32 = 5;
+
+/// Counts transactions that did not succeed, shared by every device on
+/// the bus. A transaction that is dropped half way, because the
+/// measurement timed out, counts as failed. Waiting for another device
+/// to finish with the bus does not count.
+pub struct BusMonitor {
+    failed_in_a_row: Cell<u8>,
+    stuck: Signal<NoopRawMutex, ()>,
+}
+
+impl BusMonitor {
+    pub fn new() -> Self {
+        Self {
+            failed_in_a_row: Cell::new(0),
+            stuck: Signal::new(),
+        }
+    }
+
+    /// A device on the bus that reports to this monitor
+    pub fn device<'a, BUS>(&'a self, bus: &'a Mutex<NoopRawMutex, BUS>) -> Monitored<'a, BUS> {
+        Monitored { bus, monitor: self }
+    }
+
+    /// Call once the bus is locked
+    fn started(&self) {
+        // counted as failed until it succeeds
+        let failed = self.failed_in_a_row.get().saturating_add(1);
+        self.failed_in_a_row.set(failed);
+        if failed == MAX_FAILED_IN_A_ROW {
+            self.stuck.signal(());
+        }
+    }
+
+    fn succeeded(&self) {
+        self.failed_in_a_row.set(0);
+    }
+
+    /// Recovers the bus with `recover` whenever the devices on it keep
+    /// failing. On the board that is [`recover`].
+    pub async fn recover_when_stuck<BUS>(
+        &self,
+        bus: &Mutex<NoopRawMutex, BUS>,
+        mut recover: impl FnMut(&mut BUS) -> BusFault,
+        publish: &Channel,
+    ) -> ! {
+        loop {
+            self.stuck.wait().await;
+            // no transaction can be in progress while we hold the lock
+            let fault = recover(&mut *bus.lock().await);
+            warn!("recovered i2c bus: {}", fault);
+            publish.send_status(Status::I2cBus(fault));
+            self.failed_in_a_row.set(0);
+            Timer::after(MIN_BETWEEN_RECOVERIES).await;
+        }
+    }
+}
+
+impl Default for BusMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Frees the lines and resets the peripheral
+#[cfg(feature = "board")]
+pub fn recover(i2c: &mut I2cPeripheral) -> BusFault {
+    let fault = free_the_lines();
+    reset_peripheral(i2c);
+    fault
+}
+
+/// Clocks SCL until the device holding SDA low lets go, then sends a
+/// STOP. Takes the pins from the peripheral for the duration.
+#[cfg(feature = "board")]
+fn free_the_lines() -> BusFault {
+    let gpio = pac::GPIOB;
+    let sda_high = || gpio.idr().read().idr(SDA) == vals::Idr::HIGH;
+    let set = |pin: usize, high: bool| {
+        gpio.bsrr().write(|w| {
+            if high {
+                w.set_bs(pin, true)
+            } else {
+                w.set_br(pin, true)
+            }
+        })
+    };
+
+    if sda_high() {
+        return BusFault::TransactionsFailing;
+    }
+
+    // the pins are already open drain, as gpio outputs
+    // we can drive them low and read them back
+    set(SCL, true);
+    set(SDA, true);
+    gpio.moder().modify(|w| {
+        w.set_moder(SCL, vals::Moder::OUTPUT);
+        w.set_moder(SDA, vals::Moder::OUTPUT);
+    });
+
+    // the device releases SDA at the latest after the byte it is
+    // sending and the ack bit
+    for _ in 0..9 {
+        set(SCL, false);
+        delay_us(HALF_CLOCK);
+        set(SCL, true);
+        delay_us(HALF_CLOCK);
+        if sda_high() {
+            break;
+        }
+    }
+    let released = sda_high();
+
+    // STOP: SDA goes high while SCL is high
+    set(SCL, false);
+    delay_us(HALF_CLOCK);
+    set(SDA, false);
+    delay_us(HALF_CLOCK);
+    set(SCL, true);
+    delay_us(HALF_CLOCK);
+    set(SDA, true);
+    delay_us(HALF_CLOCK);
+
+    gpio.moder().modify(|w| {
+        w.set_moder(SCL, vals::Moder::ALTERNATE);
+        w.set_moder(SDA, vals::Moder::ALTERNATE);
+    });
+    BusFault::SdaStuckLow { released }
+}
+
+/// The peripheral keeps its busy flag set after the bus got stuck,
+/// a software reset clears it but also every other register. Puts back
+/// what the driver set up at init and enables the peripheral again.
+/// Takes the peripheral so no transaction can be in progress.
+#[cfg(feature = "board")]
+fn reset_peripheral(_i2c: &mut I2cPeripheral) {
+    let regs = pac::I2C1;
+    // clock frequency, interrupt and dma enables
+    let cr2 = regs.cr2().read();
+    let ccr = regs.ccr().read();
+    let trise = regs.trise().read();
+    let oar1 = regs.oar1().read();
+
+    regs.cr1().modify(|w| w.set_swrst(true));
+    regs.cr1().modify(|w| w.set_swrst(false));
+
+    regs.cr2().write_value(cr2);
+    regs.ccr().write_value(ccr);
+    regs.trise().write_value(trise);
+    regs.oar1().write_value(oar1);
+    regs.cr1().modify(|w| w.set_pe(true));
+    info!("i2c peripheral reset");
+}
+
+/// A device on the shared bus whose transactions are counted by a
+/// [`BusMonitor`]. Locks the bus for each transaction like the
+/// `I2cDevice` of embassy-embedded-hal and gives the same errors.
+pub struct Monitored<'a, BUS> {
+    bus: &'a Mutex<NoopRawMutex, BUS>,
+    monitor: &'a BusMonitor,
+}
+
+impl<BUS: ErrorType> Monitored<'_, BUS> {
+    fn record<T>(&self, res: Result<T, BUS::Error>) -> Result<T, I2cDeviceError<BUS::Error>> {
+        if res.is_ok() {
+            self.monitor.succeeded();
+        }
+        res.map_err(I2cDeviceError::I2c)
+    }
+}
+
+impl<BUS: ErrorType> ErrorType for Monitored<'_, BUS> {
+    type Error = I2cDeviceError<BUS::Error>;
+}
+
+impl<BUS: I2c> I2c for Monitored<'_, BUS> {
+    async fn read(&mut self, address: u8, read: &mut [u8]) -> Result<(), Self::Error> {
+        let mut bus = self.bus.lock().await;
+        self.monitor.started();
+        let res = I2c::read(&mut *bus, address, read).await;
+        self.record(res)
+    }
+
+    async fn write(&mut self, address: u8, write: &[u8]) -> Result<(), Self::Error> {
+        let mut bus = self.bus.lock().await;
+        self.monitor.started();
+        let res = I2c::write(&mut *bus, address, write).await;
+        self.record(res)
+    }
+
+    async fn write_read(
+        &mut self,
+        address: u8,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        let mut bus = self.bus.lock().await;
+        self.monitor.started();
+        let res = I2c::write_read(&mut *bus, address, write, read).await;
+        self.record(res)
+    }
+
+    async fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        let mut bus = self.bus.lock().await;
+        self.monitor.started();
+        let res = I2c::transaction(&mut *bus, address, operations).await;
+        self.record(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+    use embassy_futures::select::{select, Either};
+    use embedded_hal_async::i2c::ErrorKind;
+
+    use super::*;
+
+    /// Fails every transaction while `stuck`
+    struct Bus {
+        stuck: bool,
+    }
+
+    impl ErrorType for Bus {
+        type Error = ErrorKind;
+    }
+
+    impl I2c for Bus {
+        async fn transaction(
+            &mut self,
+            _address: u8,
+            _operations: &mut [Operation<'_>],
+        ) -> Result<(), Self::Error> {
+            if self.stuck {
+                Err(ErrorKind::Bus)
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    /// Reads through a monitored device, `stuck` says for every read
+    /// whether the bus fails it. Returns how often the bus was recovered.
+    fn recoveries(stuck: &[bool]) -> usize {
+        let publish = Channel::new();
+        let bus = Mutex::new(Bus { stuck: false });
+        let monitor = BusMonitor::new();
+        let mut recovered = 0;
+
+        let recover = |bus: &mut Bus| {
+            recovered += 1;
+            bus.stuck = false;
+            BusFault::SdaStuckLow { released: true }
+        };
+        let reads = async {
+            let mut device = monitor.device(&bus);
+            for stuck in stuck {
+                bus.lock().await.stuck = *stuck;
+                let _ = device.read(0x44, &mut [0; 2]).await;
+                // lets the recovery run
+                Timer::after_millis(1).await;
+            }
+        };
+        let run = select(monitor.recover_when_stuck(&bus, recover, &publish), reads);
+        assert!(matches!(block_on(run), Either::Second(())));
+
+        if recovered > 0 {
+            let status = publish.next_status();
+            assert!(matches!(
+                status,
+                Some(Status::I2cBus(BusFault::SdaStuckLow { released: true }))
+            ));
+        }
+        recovered
+    }
+
+    #[test]
+    fn recovers_after_failing_in_a_row() {
+        let failing = [true; MAX_FAILED_IN_A_ROW as usize];
+        assert_eq!(recoveries(&failing), 1);
+    }
+
+    #[test]
+    fn success_resets_the_count() {
+        let mut results = [true; 2 * MAX_FAILED_IN_A_ROW as usize - 1];
+        results[MAX_FAILED_IN_A_ROW as usize - 1] = false;
+        assert_eq!(recoveries(&results), 0);
+    }
+}
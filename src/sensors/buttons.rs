@@ -0,0 +1,434 @@
+//! The buttons on the bed panel. Every button is debounced, each press
+//! goes out as a `BedButton` reading with its length at high priority.
+//! The presses are also turned into gestures. Buttons pressed at
+//! (nearly) the same moment form a chord, reported as one event instead
+//! of a gesture per button.
+//!
+//! The protocol's `BedButton` only carries the length of a press, the
+//! gestures and chords are send as [`ButtonEvent`] to collectors using
+//! sequenced frames and to mqtt.
+//!
+//! A button is either interrupt driven, an [`ExtiInput`], or scanned on
+//! a timer, see [`scan`]. Both give the same events and can be mixed,
+//! see [`AnyInput`].
+
+use core::cell::{Cell, RefCell};
+use core::mem;
+
+use defmt::warn;
+use embassy_futures::join;
+#[cfg(feature = "board")]
+use embassy_stm32::{exti::ExtiInput, gpio};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use heapless::Vec;
+use protocol::large_bedroom::{BedButton, LargeBedroom as LB};
+use serde::{Deserialize, Serialize};
+
+use crate::channel::Channel;
+
+#[cfg(feature = "board")]
+pub mod scan;
+
+/// Contacts bounce for a few milliseconds after changing
+const DEBOUNCE: Duration = Duration::from_millis(20);
+/// Released after at least this long is a long press
+const LONG_PRESS: Duration = Duration::from_millis(500);
+/// Still pressed after this long starts a hold
+const HOLD: Duration = Duration::from_millis(1000);
+/// Interval between repeats while held
+const REPEAT: Duration = Duration::from_millis(250);
+/// A second short press within this time after releasing the first
+/// makes it a double press
+const DOUBLE_PRESS_GAP: Duration = Duration::from_millis(300);
+/// Buttons pressed within this time of the first form a chord
+const CHORD_WINDOW: Duration = Duration::from_millis(80);
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format, Serialize, Deserialize)]
+pub enum Button {
+    TopLeft,
+    TopRight,
+    MiddleInner,
+    MiddleCenter,
+    MiddleOuter,
+    LowerInner,
+    LowerCenter,
+    LowerOuter,
+}
+
+impl Button {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Button::TopLeft => "top_left",
+            Button::TopRight => "top_right",
+            Button::MiddleInner => "middle_inner",
+            Button::MiddleCenter => "middle_center",
+            Button::MiddleOuter => "middle_outer",
+            Button::LowerInner => "lower_inner",
+            Button::LowerCenter => "lower_center",
+            Button::LowerOuter => "lower_outer",
+        }
+    }
+
+    fn pressed_for(&self, press: protocol::Press) -> BedButton {
+        match self {
+            Button::TopLeft => BedButton::TopLeft(press),
+            Button::TopRight => BedButton::TopRight(press),
+            Button::MiddleInner => BedButton::MiddleInner(press),
+            Button::MiddleCenter => BedButton::MiddleCenter(press),
+            Button::MiddleOuter => BedButton::MiddleOuter(press),
+            Button::LowerInner => BedButton::LowerInner(press),
+            Button::LowerCenter => BedButton::LowerCenter(press),
+            Button::LowerOuter => BedButton::LowerOuter(press),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format, Serialize, Deserialize)]
+pub enum Gesture {
+    /// Not followed by a second press, reported once the double press
+    /// gap has passed
+    Short,
+    Long,
+    Double,
+    /// Still pressed after [`HOLD`]
+    Hold,
+    /// Every [`REPEAT`] while still held, counting from one
+    Repeat(u16),
+}
+
+#[derive(Clone, defmt::Format, Serialize, Deserialize)]
+pub enum ButtonEvent {
+    Gesture {
+        button: Button,
+        gesture: Gesture,
+    },
+    /// Pressed together, in the order they went down
+    Chord(Vec<Button, 8>),
+}
+
+/// What a press turned out to be part of
+enum Role {
+    /// Decides whether a chord formed once the window passed
+    First,
+    /// Part of a chord, the first button reports it
+    Member,
+}
+
+/// Collects the buttons that go down together
+struct Panel {
+    chord: RefCell<Vec<Button, 8>>,
+    started: Cell<Instant>,
+}
+
+impl Panel {
+    fn new() -> Self {
+        Self {
+            chord: RefCell::new(Vec::new()),
+            started: Cell::new(Instant::from_ticks(0)),
+        }
+    }
+
+    fn pressed(&self, button: Button) -> Role {
+        let mut chord = self.chord.borrow_mut();
+        if !chord.is_empty() && self.started.get().elapsed() < CHORD_WINDOW {
+            let _ignore_full = chord.push(button);
+            return Role::Member;
+        }
+
+        chord.clear();
+        let _ignore_full = chord.push(button);
+        self.started.set(Instant::now());
+        Role::First
+    }
+
+    /// Call once the chord window passed, None if only the first
+    /// button was pressed
+    fn take_chord(&self) -> Option<Vec<Button, 8>> {
+        let chord = mem::take(&mut *self.chord.borrow_mut());
+        (chord.len() > 1).then_some(chord)
+    }
+}
+
+/// Level of a pin, a button is high while pressed. Also used for the
+/// interrupt line of the max44009, see `fast.rs`.
+// all buttons are awaited together in one task, nothing is `Send`
+#[allow(async_fn_in_trait)]
+pub trait Input {
+    fn is_high(&mut self) -> bool;
+
+    async fn wait_for_level(&mut self, high: bool);
+}
+
+/// Wakes on the edge, needs a free EXTI line
+#[cfg(feature = "board")]
+impl Input for ExtiInput<'static> {
+    fn is_high(&mut self) -> bool {
+        ExtiInput::is_high(self)
+    }
+
+    async fn wait_for_level(&mut self, high: bool) {
+        if high {
+            self.wait_for_high().await;
+        } else {
+            self.wait_for_low().await;
+        }
+    }
+}
+
+/// Lets every button use the input that suits its pin
+#[cfg(feature = "board")]
+pub enum AnyInput<'a> {
+    Exti(ExtiInput<'static>),
+    Scanned(gpio::Input<'static>),
+    Matrix(scan::MatrixButton<'a>),
+}
+
+#[cfg(feature = "board")]
+impl Input for AnyInput<'_> {
+    fn is_high(&mut self) -> bool {
+        match self {
+            AnyInput::Exti(input) => Input::is_high(input),
+            AnyInput::Scanned(input) => Input::is_high(input),
+            AnyInput::Matrix(input) => input.is_high(),
+        }
+    }
+
+    async fn wait_for_level(&mut self, high: bool) {
+        match self {
+            AnyInput::Exti(input) => input.wait_for_level(high).await,
+            AnyInput::Scanned(input) => input.wait_for_level(high).await,
+            AnyInput::Matrix(input) => input.wait_for_level(high).await,
+        }
+    }
+}
+
+/// On the board every input is an [`AnyInput`]
+pub struct ButtonInputs<I> {
+    pub top_left: I,
+    pub top_right: I,
+    pub middle_inner: I,
+    pub middle_center: I,
+    pub middle_outer: I,
+    pub lower_inner: I,
+    pub lower_center: I,
+    pub lower_outer: I,
+}
+
+pub async fn watch(inputs: ButtonInputs<impl Input>, publish: &Channel) {
+    let panel = Panel::new();
+    join::join_array([
+        watch_button(inputs.top_left, Button::TopLeft, &panel, publish),
+        watch_button(inputs.top_right, Button::TopRight, &panel, publish),
+        watch_button(inputs.middle_inner, Button::MiddleInner, &panel, publish),
+        watch_button(inputs.middle_center, Button::MiddleCenter, &panel, publish),
+        watch_button(inputs.middle_outer, Button::MiddleOuter, &panel, publish),
+        watch_button(inputs.lower_inner, Button::LowerInner, &panel, publish),
+        watch_button(inputs.lower_center, Button::LowerCenter, &panel, publish),
+        watch_button(inputs.lower_outer, Button::LowerOuter, &panel, publish),
+    ])
+    .await;
+}
+
+/// The buttons pull the input high while pressed
+async fn watch_button(mut input: impl Input, button: Button, panel: &Panel, publish: &Channel) {
+    let send = |gesture| publish.send_button(ButtonEvent::Gesture { button, gesture });
+    let released = |pressed_at| send_press(button, pressed_at, publish);
+
+    loop {
+        wait_until_stable(&mut input, true).await;
+        let pressed_at = Instant::now();
+
+        let in_chord = match panel.pressed(button) {
+            Role::Member => true,
+            Role::First => {
+                Timer::after(CHORD_WINDOW).await;
+                match panel.take_chord() {
+                    Some(chord) => {
+                        publish.send_button(ButtonEvent::Chord(chord));
+                        true
+                    }
+                    None => false,
+                }
+            }
+        };
+        if in_chord {
+            wait_until_stable(&mut input, false).await;
+            released(pressed_at);
+            continue;
+        }
+
+        let until_hold = (pressed_at + HOLD).saturating_duration_since(Instant::now());
+        if with_timeout(until_hold, wait_until_stable(&mut input, false))
+            .await
+            .is_err()
+        {
+            send(Gesture::Hold);
+            let mut repeat = 0u16;
+            while with_timeout(REPEAT, wait_until_stable(&mut input, false))
+                .await
+                .is_err()
+            {
+                repeat = repeat.saturating_add(1);
+                send(Gesture::Repeat(repeat));
+            }
+            released(pressed_at);
+            continue;
+        }
+        released(pressed_at);
+
+        if pressed_at.elapsed() >= LONG_PRESS {
+            send(Gesture::Long);
+            continue;
+        }
+
+        let second_press = wait_until_stable(&mut input, true);
+        if with_timeout(DOUBLE_PRESS_GAP, second_press).await.is_err() {
+            send(Gesture::Short);
+            continue;
+        }
+        let pressed_again_at = Instant::now();
+        send(Gesture::Double);
+        wait_until_stable(&mut input, false).await;
+        released(pressed_again_at);
+    }
+}
+
+/// Every press as the collector knows them
+fn send_press(button: Button, pressed_at: Instant, publish: &Channel) {
+    let Ok(millis) = pressed_at.elapsed().as_millis().try_into() else {
+        warn!("extremely long button press registered, skipping");
+        return;
+    };
+    let event = button.pressed_for(protocol::Press(millis));
+    publish.send_p2(LB::BedButton(event));
+}
+
+/// Returns once the input is at the `high` level and stayed there for
+/// longer then the contacts bounce
+async fn wait_until_stable(input: &mut impl Input, high: bool) {
+    loop {
+        input.wait_for_level(high).await;
+        Timer::after(DEBOUNCE).await;
+        if input.is_high() == high {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+    use embassy_futures::join::join;
+    use embassy_futures::select::select;
+
+    use super::*;
+    use crate::sensors::mock::ScriptedButton;
+
+    struct Watched {
+        events: std::vec::Vec<ButtonEvent>,
+        /// Send as `BedButton` readings
+        presses: usize,
+    }
+
+    impl Watched {
+        fn gestures(&self) -> std::vec::Vec<(Button, Gesture)> {
+            self.events
+                .iter()
+                .filter_map(|event| match event {
+                    ButtonEvent::Gesture { button, gesture } => Some((*button, *gesture)),
+                    ButtonEvent::Chord(_) => None,
+                })
+                .collect()
+        }
+
+        fn chords(&self) -> std::vec::Vec<std::vec::Vec<Button>> {
+            self.events
+                .iter()
+                .filter_map(|event| match event {
+                    ButtonEvent::Chord(buttons) => Some(buttons.to_vec()),
+                    ButtonEvent::Gesture { .. } => None,
+                })
+                .collect()
+        }
+    }
+
+    /// Watches the top left and top right button, their levels flip at
+    /// the given milliseconds
+    fn watch(left: &[u64], right: &[u64], for_ms: u64) -> Watched {
+        let publish = Channel::new();
+        let panel = Panel::new();
+        let start = Instant::now();
+        let left = ScriptedButton::new(start, left);
+        let right = ScriptedButton::new(start, right);
+
+        let buttons = join(
+            watch_button(left, Button::TopLeft, &panel, &publish),
+            watch_button(right, Button::TopRight, &panel, &publish),
+        );
+        block_on(select(buttons, Timer::after_millis(for_ms)));
+
+        let mut events = std::vec::Vec::new();
+        while let Some(event) = publish.next_button() {
+            events.push(event);
+        }
+        let mut presses = 0;
+        while publish.next_ready().is_some() {
+            presses += 1;
+        }
+        Watched { events, presses }
+    }
+
+    // A short press is stable 20 ms after going down, released at 150
+    // it is stable at 170. The double press gap then ends at 470.
+
+    #[test]
+    fn second_press_within_the_gap_is_a_double() {
+        let watched = watch(&[0, 150, 400, 500], &[], 1000);
+        assert!(watched.gestures() == [(Button::TopLeft, Gesture::Double)]);
+        assert_eq!(watched.presses, 2);
+    }
+
+    #[test]
+    fn second_press_after_the_gap_is_another_short() {
+        let watched = watch(&[0, 150, 520, 600], &[], 1400);
+        let short = (Button::TopLeft, Gesture::Short);
+        assert!(watched.gestures() == [short, short]);
+        assert_eq!(watched.presses, 2);
+    }
+
+    #[test]
+    fn long_press() {
+        let watched = watch(&[0, 700], &[], 1000);
+        assert!(watched.gestures() == [(Button::TopLeft, Gesture::Long)]);
+    }
+
+    #[test]
+    fn chord_in_the_order_pressed_whatever_the_release_order() {
+        let first_released_first = watch(&[0, 300], &[40, 400], 700);
+        let last_released_first = watch(&[0, 400], &[40, 300], 700);
+
+        for watched in [first_released_first, last_released_first] {
+            assert!(watched.chords() == [[Button::TopLeft, Button::TopRight]]);
+            assert!(watched.gestures().is_empty());
+            assert_eq!(watched.presses, 2);
+        }
+    }
+
+    #[test]
+    fn pressed_after_the_chord_window_is_no_chord() {
+        let watched = watch(&[0, 150], &[200, 300], 1200);
+        assert!(watched.chords().is_empty());
+        let gestures = watched.gestures();
+        assert!(gestures.contains(&(Button::TopLeft, Gesture::Short)));
+        assert!(gestures.contains(&(Button::TopRight, Gesture::Short)));
+    }
+
+    #[test]
+    fn long_press_in_a_chord_is_only_the_chord() {
+        // held past the hold time, no long press, hold or repeats
+        let watched = watch(&[0, 1300], &[40, 1200], 1600);
+        assert!(watched.chords() == [[Button::TopLeft, Button::TopRight]]);
+        assert!(watched.gestures().is_empty());
+        assert_eq!(watched.presses, 2);
+    }
+}
@@ -0,0 +1,118 @@
+//! Buttons on pins without a free EXTI line. There is one line per pin
+//! number, shared by all ports, so `PA5` and `PB5` can not both wake
+//! us. Scanned inputs are sampled on a timer instead and can be on any
+//! pin, including the crossings of a row/column matrix.
+
+use core::cell::{Cell, RefCell};
+
+use embassy_stm32::gpio::{self, Output};
+use embassy_time::{Duration, Instant, Timer};
+
+use super::Input;
+use crate::sensors::delay_us;
+
+/// Well below the debounce time, still leaves the cpu idle most of
+/// the time
+pub const SCAN_INTERVAL: Duration = Duration::from_millis(5);
+/// Time in µs for the columns to follow a row going high
+const SETTLE: u32 = 10;
+
+/// A button on its own pin, sampled every [`SCAN_INTERVAL`]
+impl Input for gpio::Input<'static> {
+    fn is_high(&mut self) -> bool {
+        gpio::Input::is_high(self)
+    }
+
+    async fn wait_for_level(&mut self, high: bool) {
+        while Input::is_high(self) != high {
+            Timer::after(SCAN_INTERVAL).await;
+        }
+    }
+}
+
+/// Buttons on the crossings of rows, driven high one at a time, and
+/// pulled down columns. Every button needs a diode towards its column,
+/// otherwise pressing multiple buttons shorts the rows together.
+pub struct Matrix<const ROWS: usize, const COLUMNS: usize> {
+    rows: RefCell<[Output<'static>; ROWS]>,
+    columns: [gpio::Input<'static>; COLUMNS],
+    pressed: Cell<u32>,
+    scanned_at: Cell<Option<Instant>>,
+}
+
+impl<const ROWS: usize, const COLUMNS: usize> Matrix<ROWS, COLUMNS> {
+    /// The rows should start low
+    pub fn new(rows: [Output<'static>; ROWS], columns: [gpio::Input<'static>; COLUMNS]) -> Self {
+        defmt::assert!(ROWS * COLUMNS <= u32::BITS as usize);
+        Self {
+            rows: RefCell::new(rows),
+            columns,
+            pressed: Cell::new(0),
+            scanned_at: Cell::new(None),
+        }
+    }
+
+    /// The button at the crossing of `row` and `column`
+    pub fn button(&self, row: usize, column: usize) -> MatrixButton<'_> {
+        defmt::assert!(row < ROWS && column < COLUMNS);
+        MatrixButton {
+            matrix: self,
+            bit: 1 << (row * COLUMNS + column),
+        }
+    }
+
+    fn scan(&self) -> u32 {
+        let mut pressed = 0;
+        let mut rows = self.rows.borrow_mut();
+        for (r, row) in rows.iter_mut().enumerate() {
+            row.set_high();
+            delay_us(SETTLE);
+            for (c, column) in self.columns.iter().enumerate() {
+                if column.is_high() {
+                    pressed |= 1 << (r * COLUMNS + c);
+                }
+            }
+            row.set_low();
+        }
+        pressed
+    }
+}
+
+/// Hides the size of the matrix from its buttons
+trait Pressed {
+    /// Bit `row * COLUMNS + column` is set while that button is pressed
+    fn pressed(&self) -> u32;
+}
+
+impl<const ROWS: usize, const COLUMNS: usize> Pressed for Matrix<ROWS, COLUMNS> {
+    /// The buttons share the scans, only scans if the last one is
+    /// older then the scan interval
+    fn pressed(&self) -> u32 {
+        let stale = self
+            .scanned_at
+            .get()
+            .map_or(true, |at| at.elapsed() >= SCAN_INTERVAL);
+        if stale {
+            self.pressed.set(self.scan());
+            self.scanned_at.set(Some(Instant::now()));
+        }
+        self.pressed.get()
+    }
+}
+
+pub struct MatrixButton<'a> {
+    matrix: &'a dyn Pressed,
+    bit: u32,
+}
+
+impl Input for MatrixButton<'_> {
+    fn is_high(&mut self) -> bool {
+        self.matrix.pressed() & self.bit != 0
+    }
+
+    async fn wait_for_level(&mut self, high: bool) {
+        while self.is_high() != high {
+            Timer::after(SCAN_INTERVAL).await;
+        }
+    }
+}
@@ -1,124 +1,207 @@
-use defmt::{unwrap, warn};
-use embassy_futures::{
-    join::{self, join3},
-    yield_now,
-};
-use embassy_stm32::exti::ExtiInput;
-use embassy_time::{Duration, Instant, Timer};
-use embedded_hal_async::i2c::I2c;
-use max44009::Max44009;
+use embassy_futures::yield_now;
+use embassy_time::{with_timeout, Duration, Instant, Timer};
 
+use super::buttons::Input;
+use super::health::Supervisor;
+use super::lux::{interrupt_window, sig_lux_diff, Smoother};
+use super::sensor::Sensor;
 use crate::channel::Channel;
+use crate::commands::Commands;
 
-use protocol::downcast_err::{ConcreteErrorType, I2cError};
-use protocol::large_bedroom::{self, BedButton, LargeBedroom as LB};
+use protocol::large_bedroom::{Device, LargeBedroom as LB};
 
-fn sig_lux_diff(old: f32, new: f32) -> bool {
-    let diff = old - new;
-    // we do not have f32::abs on embedded
-    diff > old / 20.0 || -diff > old / 20.0
-}
-
-async fn report_lux<I2C>(
-    mut max44: Max44009<I2C>,
+/// With an interrupt pin the sensor wakes us once the brightness
+/// leaves a window around the last reported value. Without one, or
+/// while the window is not set, the sensor is polled.
+async fn report_lux<LUX, INT>(
+    supervisor: &Supervisor<'_, LUX>,
+    mut interrupt: Option<INT>,
     publish: &Channel,
+    commands: &Commands<'_>,
 ) where
-    I2C: I2c,
-    I2C::Error: defmt::Format,
-    <I2C as embedded_hal_async::i2c::ErrorType>::Error: Into<I2cError>,
+    LUX: Sensor<Reading = f32>,
+    INT: Input,
 {
+    let mut max44 = supervisor.wait_ready().await;
+    let filter = commands.lux_filter();
+    let mut smoother = Smoother::new(filter.smoothing);
     let mut prev_lux = f32::MAX;
     let mut last_lux = Instant::now();
+    let mut window_set = false;
     const MIN_INTERVAL: Duration = Duration::from_secs(1);
 
-    // todo!("reinit devices after error");
     loop {
-        Timer::after_millis(50).await;
-        let lux = match max44.read_lux().await {
-            Ok(lux) => lux,
+        match interrupt.as_mut() {
+            // the sensor pulls the line low, time out to still report
+            // every MIN_INTERVAL if nothing changes
+            Some(interrupt) if window_set => {
+                let _ = with_timeout(MIN_INTERVAL, interrupt.wait_for_level(false)).await;
+            }
+            _ => Timer::after(commands.lux_interval()).await,
+        }
+        let res = max44.measure().await;
+        if supervisor.record(res.is_ok(), publish) {
+            max44 = supervisor.wait_ready().await;
+            smoother.reset();
+            window_set = false;
+        }
+
+        let lux = match res {
+            Ok(lux) => smoother.push(lux),
             Err(err) if last_lux.elapsed() > MIN_INTERVAL => {
-                let err = large_bedroom::SensorError::Max44(err.strip_generics());
-                let err = large_bedroom::Error::Running(err);
-                let _ignore = publish.send_error(err);
+                supervisor.report(err, publish);
+                // the interrupt might still be raised, poll until
+                // the window is set again
+                window_set = false;
+                continue;
+            }
+            Err(_) => {
+                window_set = false;
                 continue;
             }
-            Err(_) => continue,
         };
+        publish.latest().update(Device::Max44, &LB::Brightness(lux));
 
-        if sig_lux_diff(prev_lux, lux) {
-            publish.send_p2(LB::Brightness(lux))
+        if sig_lux_diff(prev_lux, lux, &filter) {
+            publish.send_stream_p2(LB::Brightness(lux));
+            prev_lux = lux;
+            last_lux = Instant::now();
         } else if last_lux.elapsed() > MIN_INTERVAL {
-            publish.send_p1(LB::Brightness(lux))
+            publish.send_stream_p1(LB::Brightness(lux));
+            prev_lux = lux;
+            last_lux = Instant::now();
         } else {
             yield_now().await;
-            continue;
         };
 
-        prev_lux = lux;
-        last_lux = Instant::now();
+        if interrupt.is_some() {
+            // also clears the interrupt that woke us
+            let (low, high) = interrupt_window(prev_lux, &filter);
+            window_set = match max44.interrupt_outside(low, high).await {
+                Ok(()) => true,
+                Err(err) => {
+                    supervisor.report(err, publish);
+                    false
+                }
+            };
+        }
     }
 }
 
-async fn watch_button(
-    mut input: ExtiInput<'static>,
-    event: impl Fn(protocol::Press) -> BedButton,
-    channel: &Channel,
-) {
-    let mut went_high_at: Option<Instant> = None;
-    loop {
-        if let Some(went_high_at) = went_high_at.take() {
-            input.wait_for_falling_edge().await;
-            let press = went_high_at.elapsed();
-            if press > Duration::from_millis(5) {
-                let Ok(press) = press.as_millis().try_into() else {
-                    warn!("extremely long button press registered, skipping");
-                    continue;
-                };
-                let event = (event)(protocol::Press(press));
-                let _ignore_full = channel.send_p2(LB::BedButton(event));
+/// Brightness, which should be reported quickly. The bed buttons are
+/// watched separately, see [`super::buttons::watch`].
+pub async fn read<LUX, INT>(
+    max44: &Supervisor<'_, LUX>,
+    lux_interrupt: Option<INT>,
+    publish: &Channel,
+    commands: &Commands<'_>,
+) where
+    LUX: Sensor<Reading = f32>,
+    INT: Input,
+{
+    report_lux(max44, lux_interrupt, publish, commands).await
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+
+    use embassy_futures::block_on;
+    use embassy_futures::select::select3;
+    use protocol::large_bedroom::Error;
+
+    use super::*;
+    use crate::config::NodeConfig;
+    use crate::sensors::health::Registry;
+
+    /// Jumps from 10 to 100 lux at `CHANGE_AT`
+    const CHANGE_AT: u64 = 300;
+
+    /// The max44009 pulls its interrupt line low until the window is
+    /// set again
+    struct Lux<'a> {
+        start: Instant,
+        raised: &'a Cell<bool>,
+        measured_at: &'a Cell<heapless::Vec<u64, 32>>,
+    }
+
+    impl Sensor for Lux<'_> {
+        type Reading = f32;
+
+        fn device(&self) -> Device {
+            Device::Max44
+        }
+
+        async fn measure(&mut self) -> Result<f32, Error> {
+            let elapsed = self.start.elapsed().as_millis();
+            let mut measured_at = self.measured_at.take();
+            measured_at.push(elapsed).unwrap();
+            self.measured_at.set(measured_at);
+            Ok(if elapsed < CHANGE_AT { 10.0 } else { 100.0 })
+        }
+
+        async fn interrupt_outside(&mut self, _low: f32, _high: f32) -> Result<(), Error> {
+            self.raised.set(false);
+            Ok(())
+        }
+    }
+
+    struct Pin<'a>(&'a Cell<bool>);
+
+    impl Input for Pin<'_> {
+        fn is_high(&mut self) -> bool {
+            !self.0.get()
+        }
+
+        async fn wait_for_level(&mut self, high: bool) {
+            while self.is_high() != high {
+                Timer::after_millis(1).await;
             }
-        } else {
-            input.wait_for_rising_edge().await;
-            went_high_at = Some(Instant::now());
         }
     }
-}
 
-pub struct ButtonInputs {
-    pub top_left: ExtiInput<'static>,
-    pub top_right: ExtiInput<'static>,
-    pub middle_inner: ExtiInput<'static>,
-    pub middle_center: ExtiInput<'static>,
-    pub middle_outer: ExtiInput<'static>,
-    pub lower_inner: ExtiInput<'static>,
-    pub lower_center: ExtiInput<'static>,
-    pub lower_outer: ExtiInput<'static>,
-}
+    /// Runs `report_lux` for half a second, returns when it measured in
+    /// milliseconds since the start
+    fn measured_at(use_interrupt: bool) -> heapless::Vec<u64, 32> {
+        let publish = Channel::new();
+        let mut sampling = NodeConfig::default().sampling;
+        sampling.lux_interval_ms = 50;
+        let health = Registry::new();
+        let commands = Commands::new(&sampling, &health);
+        let supervisor = Supervisor::new(Device::Max44, &health);
+        let raised = Cell::new(false);
+        let measured_at = Cell::new(heapless::Vec::new());
+        let start = Instant::now();
 
-pub async fn read<I2C>(
-    max44: Max44009<I2C>,
-    /*inputs: ButtonInputs,*/
-    publish: &Channel,
-) where
-    I2C: I2c,
-    I2C::Error: defmt::Format,
-    <I2C as embedded_hal_async::i2c::ErrorType>::Error: Into<I2cError>,
-{
-    // let watch_buttons_1 = join5(
-    //     watch_button(inputs.top_left, BedButton::TopLeft, publish),
-    //     watch_button(inputs.top_right, BedButton::TopRight, publish),
-    //     watch_button(inputs.middle_inner, BedButton::MiddleInner, publish),
-    //     watch_button(inputs.middle_center, BedButton::MiddleCenter, publish),
-    //     watch_button(inputs.middle_outer, BedButton::MiddleOuter, publish),
-    // );
-    //
-    // let watch_buttons_2 = join3(
-    //     watch_button(inputs.lower_inner, BedButton::LowerInner, publish),
-    //     watch_button(inputs.lower_center, BedButton::LowerCenter, publish),
-    //     watch_button(inputs.lower_outer, BedButton::LowerOuter, publish),
-    // );
-
-    let watch_lux = report_lux(max44, publish);
-    watch_lux.await;
-    // join::join3(watch_buttons_1, watch_buttons_2, watch_lux).await;
+        let run = supervisor.run(&publish, || {
+            let lux = Lux {
+                start,
+                raised: &raised,
+                measured_at: &measured_at,
+            };
+            async move { Ok(lux) }
+        });
+        let interrupt = use_interrupt.then_some(Pin(&raised));
+        let report = report_lux(&supervisor, interrupt, &publish, &commands);
+        let change = async {
+            Timer::at(start + Duration::from_millis(CHANGE_AT)).await;
+            raised.set(true);
+            Timer::at(start + Duration::from_millis(500)).await;
+        };
+        block_on(select3(run, report, change));
+        measured_at.take()
+    }
+
+    #[test]
+    fn polls_without_interrupt() {
+        assert!(measured_at(false).len() >= 6);
+    }
+
+    #[test]
+    fn interrupt_wakes_on_change() {
+        let measured_at = measured_at(true);
+        // once to set the window, then at the change
+        assert_eq!(measured_at.len(), 2);
+        assert!(measured_at[1] >= CHANGE_AT && measured_at[1] < CHANGE_AT + 50);
+    }
 }
@@ -0,0 +1,312 @@
+use core::cell::{Cell, RefCell};
+use core::future::Future;
+
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer};
+use heapless::Vec;
+use protocol::large_bedroom::{Device, Error};
+use serde::{Deserialize, Serialize};
+
+use crate::channel::Channel;
+use crate::status::Status;
+
+/// Consecutive runtime errors or timeouts after which a driver is
+/// dropped and re-created
+pub const MAX_CONSECUTIVE_ERRORS: u8 = 5;
+const MAX_INIT_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format, Serialize, Deserialize)]
+pub enum Health {
+    /// Last measurement succeeded
+    Healthy,
+    /// Recent measurements failed, still using the same driver
+    Degraded,
+    /// Driver was dropped, waiting for a new one
+    Reinitializing,
+    /// Init failed, retrying in the background
+    Failed,
+}
+
+/// Health and error count of every supervised sensor, kept up to date
+/// by the supervisors. Unlike the status messages this can not get lost.
+pub struct Registry {
+    health: RefCell<Vec<(Device, Health), 5>>,
+    changed: Signal<NoopRawMutex, ()>,
+    error_counts: RefCell<Vec<(Device, u32), 5>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self {
+            health: RefCell::new(Vec::new()),
+            changed: Signal::new(),
+            error_counts: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn set(&self, device: &Device, health: Health) {
+        let mut states = self.health.borrow_mut();
+        if let Some((_, state)) = states.iter_mut().find(|(d, _)| d == device) {
+            if *state == health {
+                return;
+            }
+            *state = health;
+        } else {
+            let _ignore_full = states.push((device.clone(), health));
+        }
+        self.changed.signal(());
+    }
+
+    /// None if the sensor is not supervised or not yet started
+    pub fn get(&self, device: &Device) -> Option<Health> {
+        self.health
+            .borrow()
+            .iter()
+            .find(|(d, _)| d == device)
+            .map(|(_, health)| *health)
+    }
+
+    /// Every supervised sensor that started
+    pub fn all(&self) -> Vec<(Device, Health), 5> {
+        self.health.borrow().clone()
+    }
+
+    /// Returns once the health of a sensor changed, can return spuriously
+    pub async fn changed(&self) {
+        self.changed.wait().await
+    }
+
+    /// Sensors that currently have a driver that works
+    pub fn working(&self) -> Vec<Device, 5> {
+        self.health
+            .borrow()
+            .iter()
+            .filter(|(_, health)| matches!(health, Health::Healthy | Health::Degraded))
+            .map(|(device, _)| device.clone())
+            .collect()
+    }
+
+    fn count_error(&self, device: &Device) {
+        let mut counts = self.error_counts.borrow_mut();
+        if let Some((_, count)) = counts.iter_mut().find(|(d, _)| d == device) {
+            *count += 1;
+        } else {
+            let _ignore_full = counts.push((device.clone(), 1));
+        }
+    }
+
+    /// Errors per device since boot, also those not send because they
+    /// repeated
+    pub fn error_counts(&self) -> Vec<(Device, u32), 5> {
+        self.error_counts.borrow().clone()
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Owns the init of a sensor driver. Hands the driver to the measure
+/// loop and creates a new one when the measure loop reports too many
+/// errors in a row.
+pub struct Supervisor<'a, T> {
+    device: Device,
+    registry: &'a Registry,
+    state: Cell<Health>,
+    consecutive_errors: Cell<u8>,
+    ready: Signal<NoopRawMutex, T>,
+    reinit: Signal<NoopRawMutex, ()>,
+    reinit_requested: Cell<bool>,
+}
+
+impl<'a, T> Supervisor<'a, T> {
+    pub fn new(device: Device, registry: &'a Registry) -> Self {
+        Self {
+            device,
+            registry,
+            state: Cell::new(Health::Reinitializing),
+            consecutive_errors: Cell::new(0),
+            ready: Signal::new(),
+            reinit: Signal::new(),
+            reinit_requested: Cell::new(false),
+        }
+    }
+
+    /// Keeps calling `init` until it succeeds, backing off exponentially
+    /// between attempts. Then waits until a re-init is needed.
+    pub async fn run<F>(&self, publish: &Channel, mut init: impl FnMut() -> F) -> !
+    where
+        F: Future<Output = Result<T, Error>>,
+    {
+        loop {
+            let mut backoff = Duration::from_secs(1);
+            let driver = loop {
+                match init().await {
+                    Ok(driver) => break driver,
+                    Err(err) => {
+                        defmt::warn!("init failed, retrying in {}: {}", backoff, err);
+                        self.report(err, publish);
+                        self.set_state(Health::Failed, publish);
+                        Timer::after(backoff).await;
+                        backoff = (backoff * 2).min(MAX_INIT_BACKOFF);
+                    }
+                }
+            };
+
+            self.consecutive_errors.set(0);
+            self.reinit_requested.set(false);
+            self.set_state(Health::Healthy, publish);
+            self.ready.signal(driver);
+            self.reinit.wait().await;
+        }
+    }
+
+    pub async fn wait_ready(&self) -> T {
+        self.ready.wait().await
+    }
+
+    /// The driver is handed back on the next [`Supervisor::record`]
+    pub fn request_reinit(&self) {
+        self.reinit_requested.set(true);
+    }
+
+    /// Call after every measurement. Returns true if the driver has to
+    /// be dropped, a new one will become ready once init succeeds.
+    #[must_use]
+    pub fn record(&self, succeeded: bool, publish: &Channel) -> bool {
+        if self.reinit_requested.take() {
+            defmt::info!("re-initializing driver on request");
+            self.set_state(Health::Reinitializing, publish);
+            self.reinit.signal(());
+            return true;
+        }
+
+        if succeeded {
+            self.consecutive_errors.set(0);
+            self.set_state(Health::Healthy, publish);
+            return false;
+        }
+
+        let errors = self.consecutive_errors.get().saturating_add(1);
+        self.consecutive_errors.set(errors);
+        if errors < MAX_CONSECUTIVE_ERRORS {
+            self.set_state(Health::Degraded, publish);
+            return false;
+        }
+
+        defmt::warn!("{} errors in a row, re-initializing driver", errors);
+        self.set_state(Health::Reinitializing, publish);
+        self.reinit.signal(());
+        true
+    }
+
+    /// Counts the error before publishing it
+    pub fn report(&self, err: Error, publish: &Channel) {
+        self.registry.count_error(&self.device);
+        publish.send_error(err);
+    }
+
+    fn set_state(&self, new: Health, publish: &Channel) {
+        self.registry.set(&self.device, new);
+        if self.state.replace(new) != new {
+            publish.send_status(Status::SensorHealth {
+                device: self.device.clone(),
+                health: new,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+
+    use embassy_futures::block_on;
+    use embassy_futures::select::{select, Either};
+
+    use super::*;
+
+    #[test]
+    fn degraded_until_too_many_errors() {
+        let publish = Channel::new();
+        let health = Registry::new();
+        let supervisor = Supervisor::<()>::new(Device::Sps30, &health);
+
+        for _ in 1..MAX_CONSECUTIVE_ERRORS {
+            assert!(!supervisor.record(false, &publish));
+            assert_eq!(health.get(&Device::Sps30), Some(Health::Degraded));
+        }
+        assert!(supervisor.record(false, &publish));
+        assert_eq!(health.get(&Device::Sps30), Some(Health::Reinitializing));
+    }
+
+    #[test]
+    fn success_resets_the_error_count() {
+        let publish = Channel::new();
+        let health = Registry::new();
+        let supervisor = Supervisor::<()>::new(Device::Sps30, &health);
+
+        for _ in 1..MAX_CONSECUTIVE_ERRORS {
+            assert!(!supervisor.record(false, &publish));
+        }
+        assert!(!supervisor.record(true, &publish));
+        assert_eq!(health.get(&Device::Sps30), Some(Health::Healthy));
+        assert!(!supervisor.record(false, &publish));
+    }
+
+    #[test]
+    fn new_driver_after_too_many_errors() {
+        let publish = Channel::new();
+        let health = Registry::new();
+        let supervisor = Supervisor::new(Device::Sps30, &health);
+        let inits = Cell::new(0);
+
+        let run = supervisor.run(&publish, || {
+            let n = inits.get() + 1;
+            inits.set(n);
+            async move { Ok(n) }
+        });
+        let measure = async {
+            let first = supervisor.wait_ready().await;
+            while !supervisor.record(false, &publish) {}
+            let second = supervisor.wait_ready().await;
+            (first, second)
+        };
+
+        let (first, second) = match block_on(select(run, measure)) {
+            Either::First(never) => never,
+            Either::Second(drivers) => drivers,
+        };
+        assert_eq!((first, second), (1, 2));
+        assert_eq!(health.get(&Device::Sps30), Some(Health::Healthy));
+    }
+
+    #[test]
+    fn reinit_on_request() {
+        let publish = Channel::new();
+        let health = Registry::new();
+        let supervisor = Supervisor::<()>::new(Device::Bme680, &health);
+
+        supervisor.request_reinit();
+        assert!(supervisor.record(true, &publish));
+        assert_eq!(health.get(&Device::Bme680), Some(Health::Reinitializing));
+    }
+
+    #[test]
+    fn errors_are_counted_per_device() {
+        let publish = Channel::new();
+        let health = Registry::new();
+        let sht = Supervisor::<()>::new(Device::Sht31, &health);
+        let mhz = Supervisor::<()>::new(Device::Mhz14, &health);
+
+        sht.report(Error::Timeout(Device::Sht31), &publish);
+        sht.report(Error::Timeout(Device::Sht31), &publish);
+        mhz.report(Error::Timeout(Device::Mhz14), &publish);
+
+        let counts = health.error_counts();
+        assert!(counts[..] == [(Device::Sht31, 2), (Device::Mhz14, 1)]);
+    }
+}
@@ -0,0 +1,193 @@
+//! Creates the sensor drivers on the peripherals of the board and runs
+//! the measure loops with them.
+
+use embassy_futures::join;
+use embassy_stm32::exti::ExtiInput;
+use embassy_stm32::i2c::I2c;
+use embassy_stm32::mode::Async;
+use embassy_stm32::peripherals::{I2C1, USART1, USART2};
+use embassy_stm32::usart::Uart;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::{with_timeout, Delay, Duration};
+use max44009::{Max44009, SlaveAddr};
+use mhzx::MHZ;
+use protocol::downcast_err::{ConcreteErrorType, UartError};
+use protocol::large_bedroom::{Device, Error, SensorError};
+use sps30_async::Sps30;
+
+use super::bus::{self, BusMonitor};
+use super::health::{Registry, Supervisor};
+use super::sensor::Max44;
+use super::uart::UartDevice;
+use super::{fast, slow};
+use crate::channel::Channel;
+use crate::commands::Commands;
+
+pub type I2cPeripheral = I2c<'static, I2C1, Async>;
+pub type I2cBus = Mutex<NoopRawMutex, I2cPeripheral>;
+type I2cDevice<'a> = bus::Monitored<'a, I2cPeripheral>;
+
+/// Starts measuring right away, sensors that fail to initialize are
+/// reported and retried in the background. They join the measurements
+/// once their init succeeds. Drivers that keep failing while measuring
+/// are re-created, see [`Supervisor`]. Every sensor is
+/// supervised, also those whose init can not fail.
+pub async fn init_then_measure(
+    publish: &Channel,
+    health: &Registry,
+    commands: &Commands<'_>,
+    i2c: I2cBus,
+    lux_interrupt: Option<ExtiInput<'static>>,
+    usart_mhz: Uart<'static, USART1, Async>,
+    usart_sps: Uart<'static, USART2, Async>,
+) -> Result<(), Error> {
+    let bus = BusMonitor::new();
+
+    let (tx, rx) = usart_mhz.split();
+    let mut usart_buf = [0u8; 9 * 10]; // 9 byte messages
+    let rx = rx.into_ring_buffered(&mut usart_buf);
+    let mhz_tx = Mutex::new(tx);
+    let mhz_rx = Mutex::new(rx);
+
+    let (tx, rx) = usart_sps.split();
+    let mut usart_buf = [0u8; 100];
+    let rx = rx.into_ring_buffered(&mut usart_buf);
+    let sps_tx = Mutex::new(tx);
+    let sps_rx = Mutex::new(rx);
+
+    let sht = Supervisor::new(Device::Sht31, health);
+    let bme = Supervisor::new(Device::Bme680, health);
+    let mhz = Supervisor::new(Device::Mhz14, health);
+    let max44 = Supervisor::new(Device::Max44, health);
+    let sps = Supervisor::new(Device::Sps30, health);
+
+    let supervise = join::join5(
+        sht.run(publish, || init_sht(&i2c, &bus)),
+        bme.run(publish, || init_bme(&i2c, &bus)),
+        mhz.run(publish, || init_mhz(&mhz_tx, &mhz_rx)),
+        max44.run(publish, || init_max44(&i2c, &bus)),
+        sps.run(publish, || init_sps(&sps_tx, &sps_rx)),
+    );
+    let init_in_background = join::join3(
+        supervise,
+        forward_reinit_requests(commands, &sht, &bme, &mhz, &max44, &sps),
+        bus.recover_when_stuck(&i2c, bus::recover, publish),
+    );
+
+    let sensors_fast = fast::read(&max44, lux_interrupt, &publish, commands);
+    let sensors_slow = slow::read(&sht, &bme, &mhz, &sps, &publish, commands);
+    join::join3(init_in_background, sensors_fast, sensors_slow).await;
+
+    defmt::unreachable!();
+}
+
+async fn forward_reinit_requests<A, B, C, D, E>(
+    commands: &Commands<'_>,
+    sht: &Supervisor<'_, A>,
+    bme: &Supervisor<'_, B>,
+    mhz: &Supervisor<'_, C>,
+    max44: &Supervisor<'_, D>,
+    sps: &Supervisor<'_, E>,
+) {
+    loop {
+        match commands.reinit.receive().await {
+            Device::Sht31 => sht.request_reinit(),
+            Device::Bme680 => bme.request_reinit(),
+            Device::Mhz14 => mhz.request_reinit(),
+            Device::Max44 => max44.request_reinit(),
+            Device::Sps30 => sps.request_reinit(),
+        }
+    }
+}
+
+/// Can not fail, the sht31 is only talked to once measured. A new
+/// driver still helps if the old one got out of step with the sensor.
+async fn init_sht<'a>(
+    i2c: &'a I2cBus,
+    bus: &'a BusMonitor,
+) -> Result<sht31::SHT31<sht31::mode::SingleShot, I2cDevice<'a>>, Error> {
+    Ok(sht31::SHT31::new(bus.device(i2c), Delay)
+        .with_mode(sht31::mode::SingleShot)
+        .with_unit(sht31::TemperatureUnit::Celsius)
+        .with_accuracy(sht31::Accuracy::High))
+}
+
+async fn init_bme<'a>(
+    i2c: &'a I2cBus,
+    bus: &'a BusMonitor,
+) -> Result<bosch_bme680::Bme680<I2cDevice<'a>, Delay>, Error> {
+    let bme_config = bosch_bme680::Configuration::default();
+    with_timeout(
+        Duration::from_secs(12),
+        bosch_bme680::Bme680::new(
+            bus.device(i2c),
+            bosch_bme680::DeviceAddress::Secondary,
+            Delay,
+            &bme_config,
+            20,
+        ),
+    )
+    .await
+    .map_err(|_| Error::SetupTimedOut(Device::Bme680))?
+    .map_err(|err| err.strip_generics())
+    .map_err(SensorError::Bme680)
+    .map_err(Error::Setup)
+}
+
+async fn init_max44<'a>(
+    i2c: &'a I2cBus,
+    bus: &'a BusMonitor,
+) -> Result<Max44<I2cDevice<'a>>, Error> {
+    let mut max44009 = Max44009::new(bus.device(i2c), SlaveAddr::default());
+    with_timeout(
+        Duration::from_millis(250),
+        max44009.set_measurement_mode(max44009::MeasurementMode::Continuous),
+    )
+    .await
+    .map_err(|_| Error::SetupTimedOut(Device::Max44))?
+    .map_err(|err| err.strip_generics())
+    .map_err(SensorError::Max44)
+    .map_err(Error::Setup)?;
+    Ok(Max44 {
+        driver: max44009,
+        registers: bus.device(i2c),
+    })
+}
+
+/// Can not fail, the mhz14 is only talked to once measured. A new
+/// driver drops any half received response.
+async fn init_mhz<'a, TX, RX>(
+    tx: &'a Mutex<NoopRawMutex, TX>,
+    rx: &'a Mutex<NoopRawMutex, RX>,
+) -> Result<MHZ<UartDevice<'a, TX>, UartDevice<'a, RX>>, Error>
+where
+    TX: embedded_io_async::Write,
+    RX: embedded_io_async::Read,
+{
+    Ok(MHZ::from_tx_rx(UartDevice::new(tx), UartDevice::new(rx)))
+}
+
+async fn init_sps<'a, TX, RX>(
+    tx: &'a Mutex<NoopRawMutex, TX>,
+    rx: &'a Mutex<NoopRawMutex, RX>,
+) -> Result<
+    Sps30<{ slow::SPS30_DRIVER_BUF_SIZE }, UartDevice<'a, TX>, UartDevice<'a, RX>, Delay>,
+    Error,
+>
+where
+    TX: embedded_io_async::Write,
+    TX::Error: defmt::Format + Into<UartError>,
+    RX: embedded_io_async::Read,
+    RX::Error: defmt::Format + Into<UartError>,
+{
+    with_timeout(
+        Duration::from_millis(100),
+        Sps30::from_tx_rx(UartDevice::new(tx), UartDevice::new(rx), Delay),
+    )
+    .await
+    .map_err(|_| Error::SetupTimedOut(Device::Sps30))?
+    .map_err(|err| err.strip_generics())
+    .map_err(SensorError::Sps30)
+    .map_err(Error::Setup)
+}
@@ -0,0 +1,175 @@
+//! When a change in brightness is worth reporting. Kept apart from the
+//! loop in `fast.rs`, which needs the interrupt pin of the board, so
+//! it can be tested on the host.
+
+use core::cmp::Ordering;
+
+use heapless::{Deque, Vec};
+
+use crate::config::{LuxFilter, Smoothing};
+
+const MAX_WINDOW: usize = 16;
+
+/// Whether we would notice the change. Compares the ratio rather then
+/// the difference as we perceive brightness logarithmically.
+pub fn sig_lux_diff(old: f32, new: f32, filter: &LuxFilter) -> bool {
+    let old = old.max(filter.floor_lux);
+    let new = new.max(filter.floor_lux);
+    let (low, high) = if old < new { (old, new) } else { (new, old) };
+    high > low * filter.min_ratio
+}
+
+/// Low and high threshold for the max44009 interrupt. Brightness
+/// outside it would be a significant change from `reported`.
+pub fn interrupt_window(reported: f32, filter: &LuxFilter) -> (f32, f32) {
+    let center = reported.max(filter.floor_lux);
+    (center / filter.min_ratio, center * filter.min_ratio)
+}
+
+/// Smooths out sensor noise and the flicker of PWM dimmed lights
+pub struct Smoother {
+    smoothing: Smoothing,
+    window: Deque<f32, MAX_WINDOW>,
+}
+
+impl Smoother {
+    pub fn new(smoothing: Smoothing) -> Self {
+        Self {
+            smoothing,
+            window: Deque::new(),
+        }
+    }
+
+    pub fn push(&mut self, lux: f32) -> f32 {
+        let len = match self.smoothing {
+            Smoothing::None => return lux,
+            Smoothing::MovingAverage(n) | Smoothing::Median(n) => (n as usize).clamp(1, MAX_WINDOW),
+        };
+        while self.window.len() >= len {
+            self.window.pop_front();
+        }
+        let _ignore_full = self.window.push_back(lux);
+
+        if let Smoothing::MovingAverage(_) = self.smoothing {
+            return self.window.iter().sum::<f32>() / self.window.len() as f32;
+        }
+        let mut sorted: Vec<f32, MAX_WINDOW> = self.window.iter().copied().collect();
+        sorted.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+        let mid = sorted.len() / 2;
+        if sorted.len() % 2 == 0 {
+            (sorted[mid - 1] + sorted[mid]) / 2.0
+        } else {
+            sorted[mid]
+        }
+    }
+
+    /// Readings of a previous driver say nothing about the new one
+    pub fn reset(&mut self) {
+        self.window.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILTER: LuxFilter = LuxFilter {
+        smoothing: Smoothing::None,
+        min_ratio: 1.1,
+        floor_lux: 1.0,
+    };
+
+    #[test]
+    fn ratio_not_difference() {
+        assert!(!sig_lux_diff(100.0, 109.0, &FILTER));
+        assert!(sig_lux_diff(100.0, 111.0, &FILTER));
+        assert!(sig_lux_diff(111.0, 100.0, &FILTER));
+        // the same difference is significant in the dark
+        assert!(sig_lux_diff(10.0, 19.0, &FILTER));
+    }
+
+    #[test]
+    fn changes_below_the_floor_are_ignored() {
+        assert!(!sig_lux_diff(0.1, 0.9, &FILTER));
+        assert!(!sig_lux_diff(0.5, 1.05, &FILTER));
+        assert!(sig_lux_diff(0.5, 1.2, &FILTER));
+    }
+
+    #[test]
+    fn first_reading_is_significant() {
+        assert!(sig_lux_diff(f32::MAX, 100.0, &FILTER));
+    }
+
+    #[test]
+    fn window_around_the_reported_value() {
+        let (low, high) = interrupt_window(100.0, &FILTER);
+        assert!((low - 100.0 / 1.1).abs() < 1e-3);
+        assert!((high - 110.0).abs() < 1e-3);
+        // just outside the window is a change worth reporting
+        assert!(sig_lux_diff(100.0, high * 1.001, &FILTER));
+        assert!(sig_lux_diff(100.0, low * 0.999, &FILTER));
+    }
+
+    #[test]
+    fn average_over_what_we_have_until_full() {
+        let mut smoother = Smoother::new(Smoothing::MovingAverage(3));
+        assert_eq!(smoother.push(3.0), 3.0);
+        assert_eq!(smoother.push(6.0), 4.5);
+        assert_eq!(smoother.push(9.0), 6.0);
+        // the 3 drops out
+        assert_eq!(smoother.push(12.0), 9.0);
+    }
+
+    #[test]
+    fn median_ignores_an_outlier() {
+        let mut smoother = Smoother::new(Smoothing::Median(3));
+        smoother.push(100.0);
+        smoother.push(0.0);
+        assert_eq!(smoother.push(102.0), 100.0);
+    }
+
+    #[test]
+    fn median_of_an_even_count_is_the_mean_of_the_middle() {
+        let mut smoother = Smoother::new(Smoothing::Median(4));
+        assert_eq!(smoother.push(10.0), 10.0);
+        assert_eq!(smoother.push(20.0), 15.0);
+        smoother.push(1000.0);
+        assert_eq!(smoother.push(30.0), 25.0);
+    }
+
+    #[test]
+    fn window_is_clamped() {
+        let mut smoother = Smoother::new(Smoothing::MovingAverage(0));
+        smoother.push(1.0);
+        assert_eq!(smoother.push(5.0), 5.0);
+
+        let mut smoother = Smoother::new(Smoothing::MovingAverage(200));
+        for _ in 0..MAX_WINDOW {
+            smoother.push(0.0);
+        }
+        assert_eq!(smoother.push(16.0), 1.0);
+    }
+
+    #[test]
+    fn reset_forgets_the_old_driver() {
+        let mut smoother = Smoother::new(Smoothing::MovingAverage(4));
+        smoother.push(1000.0);
+        smoother.push(1000.0);
+        smoother.reset();
+        assert_eq!(smoother.push(10.0), 10.0);
+    }
+
+    #[test]
+    fn passed_through_without_smoothing() {
+        let mut smoother = Smoother::new(Smoothing::None);
+        smoother.push(1.0);
+        assert_eq!(smoother.push(7.0), 7.0);
+    }
+
+    #[test]
+    fn window_never_below_the_floor() {
+        let (low, high) = interrupt_window(0.0, &FILTER);
+        assert!((low - 1.0 / 1.1).abs() < 1e-6);
+        assert!((high - 1.1).abs() < 1e-6);
+    }
+}
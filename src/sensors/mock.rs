@@ -0,0 +1,81 @@
+use embassy_time::{Instant, Timer};
+use protocol::large_bedroom::{Device, Error};
+
+use super::buttons::Input;
+use super::sensor::Sensor;
+
+pub enum Step<R> {
+    Reading(R),
+    Error(Error),
+    /// Never finishes, use to test timeouts
+    Hang,
+}
+
+/// Plays back a script of readings, errors and hangs. Keeps repeating
+/// the last step once the script runs out.
+pub struct Scripted<'a, R> {
+    device: Device,
+    script: &'a [Step<R>],
+    next: usize,
+}
+
+impl<'a, R> Scripted<'a, R> {
+    pub fn new(device: Device, script: &'a [Step<R>]) -> Self {
+        assert!(!script.is_empty(), "script needs at least one step");
+        Self {
+            device,
+            script,
+            next: 0,
+        }
+    }
+
+    /// Number of times the sensor has been measured
+    pub fn measured(&self) -> usize {
+        self.next
+    }
+}
+
+impl<R: Clone> Sensor for Scripted<'_, R> {
+    type Reading = R;
+
+    fn device(&self) -> Device {
+        self.device.clone()
+    }
+
+    async fn measure(&mut self) -> Result<Self::Reading, Error> {
+        let step = self.next.min(self.script.len() - 1);
+        self.next += 1;
+        match &self.script[step] {
+            Step::Reading(reading) => Ok(reading.clone()),
+            Step::Error(err) => Err(err.clone()),
+            Step::Hang => core::future::pending().await,
+        }
+    }
+}
+
+/// Plays back the level of a button. It starts out low and flips at
+/// each of the `edges`, in milliseconds since `start`.
+pub struct ScriptedButton<'a> {
+    start: Instant,
+    edges: &'a [u64],
+}
+
+impl<'a> ScriptedButton<'a> {
+    pub fn new(start: Instant, edges: &'a [u64]) -> Self {
+        Self { start, edges }
+    }
+}
+
+impl Input for ScriptedButton<'_> {
+    fn is_high(&mut self) -> bool {
+        let elapsed = self.start.elapsed().as_millis();
+        let flipped = self.edges.iter().filter(|at| **at <= elapsed).count();
+        flipped % 2 == 1
+    }
+
+    async fn wait_for_level(&mut self, high: bool) {
+        while self.is_high() != high {
+            Timer::after_millis(1).await;
+        }
+    }
+}
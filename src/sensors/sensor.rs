@@ -0,0 +1,283 @@
+use bosch_bme680::{Bme680, MeasurementData};
+use embassy_time::{with_timeout, Delay, Duration, Timer};
+use embedded_hal_async::delay::DelayNs;
+use embedded_hal_async::i2c::I2c;
+use max44009::Max44009;
+use mhzx::MHZ;
+use protocol::downcast_err::{ConcreteErrorType, I2cError, UartError};
+use protocol::large_bedroom::{Device, Error, SensorError};
+use sht31::mode::{Sht31Measure, Sht31Reader, SingleShot};
+use sht31::SHT31;
+use sps30_async as sps30;
+use sps30_async::Sps30;
+
+use super::slow::SPS30_DRIVER_BUF_SIZE;
+
+/// How often the sps30 has a new measurement
+pub const SPS30_UPDATE_PERIOD: Duration = Duration::from_secs(1);
+/// If we are early we poll until the new measurement is there
+const SPS30_NOT_READY_POLL: Duration = Duration::from_millis(20);
+
+/// What the measure loops need from a sensor. Hides the concrete driver
+/// so the loops can also run against scripted sensors, see `mock.rs`.
+// only used on the single threaded executors, the futures never need
+// to be `Send`
+#[allow(async_fn_in_trait)]
+pub trait Sensor {
+    type Reading;
+
+    fn device(&self) -> Device;
+
+    /// Called before sleeping in between measurements, for sensors
+    /// that measure in two steps.
+    async fn start_measurement(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn measure(&mut self) -> Result<Self::Reading, Error>;
+
+    /// Maintenance requested by the collector, most sensors need none
+    async fn clean(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// For sensors with an interrupt pin: raise the interrupt once the
+    /// reading leaves the window from `low` to `high`. Clears a pending
+    /// interrupt.
+    async fn interrupt_outside(&mut self, _low: f32, _high: f32) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// A measurement that took longer then `timeout` is reported as
+/// [`Error::Timeout`].
+pub async fn measure_with_timeout<S: Sensor>(
+    sensor: &mut S,
+    timeout: Duration,
+) -> Result<S::Reading, Error> {
+    with_timeout(timeout, sensor.measure())
+        .await
+        .unwrap_or_else(|_| Err(Error::Timeout(sensor.device())))
+}
+
+impl<I2C> Sensor for SHT31<SingleShot, I2C>
+where
+    I2C: I2c,
+    I2C::Error: defmt::Format,
+    <I2C as embedded_hal_async::i2c::ErrorType>::Error: Into<I2cError>,
+{
+    type Reading = sht31::Reading;
+
+    fn device(&self) -> Device {
+        Device::Sht31
+    }
+
+    // sht works in two steps
+    //  - send measure command before sleep
+    //  - then read
+    async fn start_measurement(&mut self) -> Result<(), Error> {
+        Sht31Measure::measure(self)
+            .await
+            .map_err(SensorError::Sht31)
+            .map_err(Error::Running)
+    }
+
+    async fn measure(&mut self) -> Result<Self::Reading, Error> {
+        Sht31Reader::read(self)
+            .await
+            .map_err(SensorError::Sht31)
+            .map_err(Error::Running)
+    }
+}
+
+impl<I2C, D> Sensor for Bme680<I2C, D>
+where
+    D: DelayNs,
+    I2C: I2c,
+    I2C::Error: defmt::Format,
+    <I2C as embedded_hal_async::i2c::ErrorType>::Error: Into<I2cError>,
+{
+    type Reading = MeasurementData;
+
+    fn device(&self) -> Device {
+        Device::Bme680
+    }
+
+    async fn measure(&mut self) -> Result<Self::Reading, Error> {
+        Bme680::measure(self)
+            .await
+            .map_err(|err| err.strip_generics())
+            .map_err(SensorError::Bme680)
+            .map_err(Error::Running)
+    }
+}
+
+impl<TX, RX> Sensor for MHZ<TX, RX>
+where
+    TX: embedded_io_async::Write,
+    TX::Error: defmt::Format + Into<UartError>,
+    RX: embedded_io_async::Read,
+    RX::Error: defmt::Format + Into<UartError>,
+{
+    type Reading = mhzx::Measurement;
+
+    fn device(&self) -> Device {
+        Device::Mhz14
+    }
+
+    async fn measure(&mut self) -> Result<Self::Reading, Error> {
+        self.read_co2()
+            .await
+            .map_err(|err| err.strip_generics())
+            .map_err(SensorError::Mhz14)
+            .map_err(Error::Running)
+    }
+}
+
+impl<TX, RX> Sensor for Sps30<SPS30_DRIVER_BUF_SIZE, TX, RX, Delay>
+where
+    TX: embedded_io_async::Write,
+    TX::Error: defmt::Format + Into<UartError>,
+    RX: embedded_io_async::Read,
+    RX::Error: defmt::Format + Into<UartError>,
+{
+    type Reading = sps30::Measurement;
+
+    fn device(&self) -> Device {
+        Device::Sps30
+    }
+
+    /// Never returns while no measurement is ready, the timeout of the
+    /// measure loop reports that as [`Error::Timeout`].
+    async fn measure(&mut self) -> Result<Self::Reading, Error> {
+        loop {
+            match self.read_measurement().await {
+                Ok(Some(measurement)) => return Ok(measurement),
+                Ok(None) => Timer::after(SPS30_NOT_READY_POLL).await,
+                Err(err) => return Err(Error::Running(SensorError::Sps30(err.strip_generics()))),
+            }
+        }
+    }
+
+    async fn clean(&mut self) -> Result<(), Error> {
+        self.start_fan_cleaning()
+            .await
+            .map_err(|err| err.strip_generics())
+            .map_err(SensorError::Sps30)
+            .map_err(Error::Running)
+    }
+}
+
+/// The max44009 driver does not support the interrupt thresholds,
+/// these are programmed through a second device on the same bus.
+pub struct Max44<I2C> {
+    pub driver: Max44009<I2C>,
+    pub registers: I2C,
+}
+
+/// Address with A0 low, the default of the driver
+const MAX44_ADDRESS: u8 = 0x4A;
+/// Reading clears the interrupt
+const MAX44_INT_STATUS: u8 = 0x00;
+const MAX44_INT_ENABLE: u8 = 0x01;
+const MAX44_UPPER_THRESHOLD: u8 = 0x05;
+const MAX44_LOWER_THRESHOLD: u8 = 0x06;
+/// In steps of 100 ms, how long the reading has to stay outside the
+/// window before the interrupt is raised
+const MAX44_THRESHOLD_TIMER: u8 = 0x07;
+
+/// Lux in the threshold format: a 4 bit exponent and the upper 4 bits
+/// of the 8 bit mantissa, lux = 2^exponent * mantissa * 0.045
+fn max44_threshold(lux: f32) -> u8 {
+    // float to int casts saturate, negative becomes 0
+    let mut mantissa = (lux / 0.045) as u32;
+    let mut exponent = 0u8;
+    // exponent 15 means overrange
+    while mantissa > 0xFF && exponent < 14 {
+        mantissa >>= 1;
+        exponent += 1;
+    }
+    let mantissa = mantissa.min(0xFF) as u8;
+    exponent << 4 | mantissa >> 4
+}
+
+impl<I2C> Sensor for Max44<I2C>
+where
+    I2C: I2c,
+    I2C::Error: defmt::Format,
+    <I2C as embedded_hal_async::i2c::ErrorType>::Error: Into<I2cError>,
+{
+    type Reading = f32;
+
+    fn device(&self) -> Device {
+        Device::Max44
+    }
+
+    async fn measure(&mut self) -> Result<Self::Reading, Error> {
+        self.driver
+            .read_lux()
+            .await
+            .map_err(|err| err.strip_generics())
+            .map_err(SensorError::Max44)
+            .map_err(Error::Running)
+    }
+
+    async fn interrupt_outside(&mut self, low: f32, high: f32) -> Result<(), Error> {
+        let mut status = [0];
+        let writes = [
+            [MAX44_INT_ENABLE, 0],
+            [MAX44_UPPER_THRESHOLD, max44_threshold(high)],
+            [MAX44_LOWER_THRESHOLD, max44_threshold(low)],
+            // light flickering at mains frequency should not trigger it
+            [MAX44_THRESHOLD_TIMER, 1],
+            [MAX44_INT_ENABLE, 1],
+        ];
+        let res = async {
+            self.registers
+                .write_read(MAX44_ADDRESS, &[MAX44_INT_STATUS], &mut status)
+                .await?;
+            for write in writes {
+                self.registers.write(MAX44_ADDRESS, &write).await?;
+            }
+            Ok::<_, I2C::Error>(())
+        };
+        res.await
+            .map_err(max44009::Error::I2C)
+            .map_err(|err| err.strip_generics())
+            .map_err(SensorError::Max44)
+            .map_err(Error::Running)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+
+    use super::*;
+    use crate::sensors::mock::{Scripted, Step};
+
+    #[test]
+    fn hanging_measurement_is_a_timeout() {
+        let script = [Step::<f32>::Hang];
+        let mut sensor = Scripted::new(Device::Max44, &script);
+
+        let res = block_on(measure_with_timeout(&mut sensor, Duration::from_millis(10)));
+        assert!(matches!(res, Err(Error::Timeout(Device::Max44))));
+        assert_eq!(sensor.measured(), 1);
+    }
+
+    #[test]
+    fn readings_and_errors_pass_through() {
+        let script = [
+            Step::Reading(1.5),
+            Step::Error(Error::SetupTimedOut(Device::Max44)),
+        ];
+        let mut sensor = Scripted::new(Device::Max44, &script);
+
+        let timeout = Duration::from_millis(10);
+        let res = block_on(measure_with_timeout(&mut sensor, timeout));
+        assert!(matches!(res, Ok(lux) if lux == 1.5));
+        let res = block_on(measure_with_timeout(&mut sensor, timeout));
+        assert!(matches!(res, Err(Error::SetupTimedOut(Device::Max44))));
+    }
+}
@@ -1,102 +1,157 @@
-use core::fmt;
-
 use defmt::unwrap;
-use embassy_futures::{join, yield_now};
-use embassy_time::{with_timeout, Delay, Duration, Timer};
-use embedded_hal_async::delay::DelayNs;
-use embedded_hal_async::i2c::I2c;
-
-use mhzx::MHZ;
-use protocol::downcast_err::{ConcreteErrorType, I2cError, UartError};
-use protocol::large_bedroom::{Device, LargeBedroom as LB};
-
-use bosch_bme680::{Bme680, MeasurementData};
-use sht31::mode::{Sht31Measure, Sht31Reader, SingleShot};
-use sht31::SHT31;
+use embassy_futures::{join, select};
+use embassy_time::{Duration, Instant, Timer};
+
+use protocol::large_bedroom::{Device, Error, LargeBedroom as LB};
+
+use bosch_bme680::MeasurementData;
 use sps30_async as sps30;
-use sps30_async::Sps30;
 
+use super::health::Supervisor;
+use super::sensor::{measure_with_timeout, Sensor, SPS30_UPDATE_PERIOD};
 use crate::channel::Channel;
+use crate::commands::Commands;
 
 const SPS30_UART_BUF_SIZE: usize = 100;
-const SPS30_DRIVER_BUF_SIZE: usize = 2 * SPS30_UART_BUF_SIZE;
+pub const SPS30_DRIVER_BUF_SIZE: usize = 2 * SPS30_UART_BUF_SIZE;
+
+const SHT_TIMEOUT: Duration = Duration::from_millis(100);
+/// Includes heating up the gas sensor plate
+const BME_TIMEOUT: Duration = Duration::from_secs(1);
+const MHZ_TIMEOUT: Duration = Duration::from_millis(100);
+/// Polling for the next measurement can take a whole update period
+const SPS_TIMEOUT: Duration = Duration::from_millis(SPS30_UPDATE_PERIOD.as_millis() + 500);
 
-pub async fn read<I2C, TX1, RX1, TX2, RX2>(
-    mut sht: SHT31<SingleShot, I2C>,
-    mut bme: Bme680<I2C, impl DelayNs>,
-    mut mhz: MHZ<TX1, RX1>,
-    mut sps: Sps30<SPS30_DRIVER_BUF_SIZE, TX2, RX2, Delay>,
+/// Every sensor is measured by its own loop on its own schedule, a
+/// sensor that hangs only delays its own readings. Each measurement
+/// has a timeout, timeouts are reported like any other error.
+///
+/// Every sensor is measured once its supervisor hands us a driver. We
+/// hand the driver back if it keeps failing.
+pub async fn read<SHT, BME, MHZ, SPS>(
+    sht_supervisor: &Supervisor<'_, SHT>,
+    bme_supervisor: &Supervisor<'_, BME>,
+    mhz_supervisor: &Supervisor<'_, MHZ>,
+    sps_supervisor: &Supervisor<'_, SPS>,
     publish: &Channel,
+    commands: &Commands<'_>,
 ) where
-    I2C: I2c,
-    I2C::Error: defmt::Format,
-    <I2C as embedded_hal_async::i2c::ErrorType>::Error: Into<I2cError>,
-    TX1: embedded_io_async::Write,
-    TX1::Error: defmt::Format + Into<UartError>,
-    RX1: embedded_io_async::Read,
-    RX1::Error: defmt::Format + Into<UartError>,
-    TX2: embedded_io_async::Write,
-    TX2::Error: defmt::Format + Into<UartError>,
-    RX2: embedded_io_async::Read,
-    RX2::Error: defmt::Format + Into<UartError>,
+    SHT: Sensor<Reading = sht31::Reading>,
+    BME: Sensor<Reading = MeasurementData>,
+    MHZ: Sensor<Reading = mhzx::Measurement>,
+    SPS: Sensor<Reading = sps30::Measurement>,
 {
-    // sht works in two steps
-    //  - send measure command before sleep
-    //  - then read
-    if let Err(err) = sht.measure().await {
-        let err = protocol::large_bedroom::SensorError::Sht31(err);
-        let err = protocol::large_bedroom::Error::Running(err);
-        publish.send_error(err)
+    join::join4(
+        read_sht(sht_supervisor, publish, commands),
+        read_bme(bme_supervisor, publish, commands),
+        read_mhz(mhz_supervisor, publish, commands),
+        read_sps(sps_supervisor, publish, commands),
+    )
+    .await;
+}
+
+async fn read_sht<SHT>(supervisor: &Supervisor<'_, SHT>, publish: &Channel, commands: &Commands<'_>)
+where
+    SHT: Sensor<Reading = sht31::Reading>,
+{
+    let mut sht = supervisor.wait_ready().await;
+    let mut turn = Instant::from_ticks(0);
+    loop {
+        if let Err(err) = sht.start_measurement().await {
+            supervisor.report(err, publish)
+        }
+        turn = wait_for_turn(Device::Sht31, turn, commands).await;
+        let res = measure_with_timeout(&mut sht, SHT_TIMEOUT).await;
+        let reinit = supervisor.record(res.is_ok(), publish);
+        publish_sht_result(res, supervisor, publish);
+        if reinit {
+            sht = supervisor.wait_ready().await;
+        }
     }
-    Timer::after_secs(1).await;
+}
 
+async fn read_bme<BME>(supervisor: &Supervisor<'_, BME>, publish: &Channel, commands: &Commands<'_>)
+where
+    BME: Sensor<Reading = MeasurementData>,
+{
+    let mut bme = supervisor.wait_ready().await;
+    let mut turn = Instant::from_ticks(0);
     loop {
-        defmt::info!("this is where we break");
-        let sht_read = with_timeout(Duration::from_millis(100), sht.read());
-        yield_now().await;
-        let bme_measure = bme.measure();
-        yield_now().await;
-        let mhz_measure = with_timeout(Duration::from_millis(100), mhz.read_co2());
-        yield_now().await;
-        let sps_measure = with_timeout(Duration::from_millis(100), sps.read_measurement());
-        yield_now().await; // with this yield enabled the program hangs within 12 seconds
-        let (bme_res, sht_res, mhz_res, sps_res) =
-            join::join4(bme_measure, sht_read, mhz_measure, sps_measure).await;
-        // let (bme_res, sht_res, mhz_res) = join::join3(bme_measure, sht_read, mhz_measure).await;
-        yield_now().await;
-
-        publish_bme_result(bme_res, publish);
-        yield_now().await;
-        publish_sht_result(sht_res, publish);
-        yield_now().await;
-        publish_mhz_result(mhz_res, publish);
-        yield_now().await;
-        publish_sps_result(sps_res, publish);
-
-        // sht works in two steps
-        //  - send measure command before sleep
-        //  - then read
-        if let Err(err) = sht.measure().await {
-            let err = protocol::large_bedroom::SensorError::Sht31(err);
-            let err = protocol::large_bedroom::Error::Running(err);
-            publish.send_error(err)
+        turn = wait_for_turn(Device::Bme680, turn, commands).await;
+        let res = measure_with_timeout(&mut bme, BME_TIMEOUT).await;
+        let reinit = supervisor.record(res.is_ok(), publish);
+        publish_bme_result(res, supervisor, publish);
+        if reinit {
+            bme = supervisor.wait_ready().await;
         }
-        Timer::after_secs(1).await;
     }
 }
 
-fn publish_sps_result<TxError, RxError>(
-    sps_res: Result<
-        Result<Option<sps30::Measurement>, sps30::Error<TxError, RxError>>,
-        embassy_time::TimeoutError,
-    >,
-    publish: &Channel,
-) where
-    TxError: fmt::Debug + defmt::Format + Into<UartError>,
-    RxError: fmt::Debug + defmt::Format + Into<UartError>,
+async fn read_mhz<MHZ>(supervisor: &Supervisor<'_, MHZ>, publish: &Channel, commands: &Commands<'_>)
+where
+    MHZ: Sensor<Reading = mhzx::Measurement>,
 {
+    let mut mhz = supervisor.wait_ready().await;
+    let mut turn = Instant::from_ticks(0);
+    loop {
+        turn = wait_for_turn(Device::Mhz14, turn, commands).await;
+        let res = measure_with_timeout(&mut mhz, MHZ_TIMEOUT).await;
+        let reinit = supervisor.record(res.is_ok(), publish);
+        publish_mhz_result(res, supervisor, publish);
+        if reinit {
+            mhz = supervisor.wait_ready().await;
+        }
+    }
+}
+
+async fn read_sps<SPS>(supervisor: &Supervisor<'_, SPS>, publish: &Channel, commands: &Commands<'_>)
+where
+    SPS: Sensor<Reading = sps30::Measurement>,
+{
+    let mut sps = supervisor.wait_ready().await;
+    let mut turn = Instant::from_ticks(0);
+    loop {
+        turn = wait_for_turn(Device::Sps30, turn, commands).await;
+        let res = measure_with_timeout(&mut sps, SPS_TIMEOUT).await;
+        let reinit = supervisor.record(res.is_ok(), publish);
+        publish_sps_result(res, supervisor, publish);
+        if reinit {
+            sps = supervisor.wait_ready().await;
+            continue;
+        }
+
+        if commands.clean_fan.try_take().is_some() {
+            if let Err(err) = sps.clean().await {
+                supervisor.report(err, publish)
+            }
+        }
+    }
+}
+
+/// Sleeps until it is `device`'s turn, or until the collector asks for
+/// a measurement. `previous` is the start of the previous turn, returns
+/// the start of this one.
+async fn wait_for_turn(device: Device, previous: Instant, commands: &Commands<'_>) -> Instant {
+    let scheduled = commands.schedule(&device);
+    let schedule = scheduled.get();
+    let interval = schedule.interval().as_ticks().max(1);
+    let due = if schedule.together {
+        // the next multiple of the interval
+        Instant::from_ticks((previous.as_ticks() / interval + 1) * interval)
+    } else {
+        Instant::now() + schedule.interval()
+    };
+    select::select(Timer::at(due), scheduled.measure_now.wait()).await;
+    Instant::now()
+}
+
+fn publish_sps_result<T>(
+    sps_res: Result<sps30::Measurement, Error>,
+    supervisor: &Supervisor<'_, T>,
+    publish: &Channel,
+) {
     match sps_res {
-        Ok(Ok(Some(sps30::Measurement {
+        Ok(sps30::Measurement {
             mass_pm1_0,
             mass_pm2_5,
             mass_pm4_0,
@@ -107,91 +162,61 @@ fn publish_sps_result<TxError, RxError>(
             number_pm4_0,
             number_pm10,
             typical_particle_size,
-        }))) => {
-            publish.send_p0(LB::MassPm1_0(mass_pm1_0));
-            publish.send_p0(LB::MassPm2_5(mass_pm2_5));
-            publish.send_p0(LB::MassPm4_0(mass_pm4_0));
-            publish.send_p0(LB::MassPm10(mass_pm10));
-            publish.send_p0(LB::MassPm0_5(mass_pm0_5));
-            publish.send_p0(LB::NumberPm1_0(number_pm1_0));
-            publish.send_p0(LB::NumberPm2_5(number_pm2_5));
-            publish.send_p0(LB::NumberPm4_0(number_pm4_0));
-            publish.send_p0(LB::NumberPm10(number_pm10));
-            publish.send_p0(LB::TypicalParticleSize(typical_particle_size));
-        }
-        Ok(Ok(None)) => {
-            defmt::todo!("no idea when we hit this");
-        }
-        Ok(Err(err)) => {
-            let err = err.strip_generics();
-            let err = protocol::large_bedroom::SensorError::Sps30(err);
-            let err = protocol::large_bedroom::Error::Running(err);
-            publish.send_error(err)
-        }
-        Err(_timeout) => {
-            let err = protocol::large_bedroom::Error::Timeout(Device::Sps30);
-            publish.send_error(err)
+        }) => {
+            for value in [
+                LB::MassPm1_0(mass_pm1_0),
+                LB::MassPm2_5(mass_pm2_5),
+                LB::MassPm4_0(mass_pm4_0),
+                LB::MassPm10(mass_pm10),
+                LB::MassPm0_5(mass_pm0_5),
+                LB::NumberPm1_0(number_pm1_0),
+                LB::NumberPm2_5(number_pm2_5),
+                LB::NumberPm4_0(number_pm4_0),
+                LB::NumberPm10(number_pm10),
+                LB::TypicalParticleSize(typical_particle_size),
+            ] {
+                publish_p0(Device::Sps30, value, publish);
+            }
         }
+        Err(err) => supervisor.report(err, publish),
     }
 }
 
-fn publish_mhz_result<TxError, RxError>(
-    mhz_res: Result<
-        Result<mhzx::Measurement, mhzx::Error<TxError, RxError>>,
-        embassy_time::TimeoutError,
-    >,
+fn publish_mhz_result<T>(
+    mhz_res: Result<mhzx::Measurement, Error>,
+    supervisor: &Supervisor<'_, T>,
     publish: &Channel,
-) where
-    TxError: fmt::Debug + defmt::Format + Into<UartError>,
-    RxError: fmt::Debug + defmt::Format + Into<UartError>,
-{
+) {
     match mhz_res {
-        Ok(Ok(mhzx::Measurement { co2, .. })) => {
-            publish.send_p0(LB::Co2(co2));
-        }
-        Ok(Err(err)) => {
-            let err = err.strip_generics();
-            let err = protocol::large_bedroom::SensorError::Mhz14(err);
-            let err = protocol::large_bedroom::Error::Running(err);
-            publish.send_error(err)
-        }
-        Err(_timeout) => {
-            let err = protocol::large_bedroom::Error::Timeout(Device::Mhz14);
-            publish.send_error(err)
+        Ok(mhzx::Measurement { co2, .. }) => {
+            publish_p0(Device::Mhz14, LB::Co2(co2), publish);
         }
+        Err(err) => supervisor.report(err, publish),
     }
 }
 
-fn publish_sht_result(
-    sht_res: Result<Result<sht31::prelude::Reading, sht31::SHTError>, embassy_time::TimeoutError>,
+fn publish_sht_result<T>(
+    sht_res: Result<sht31::Reading, Error>,
+    supervisor: &Supervisor<'_, T>,
     publish: &Channel,
 ) {
     match sht_res {
-        Ok(Ok(sht31::Reading {
+        Ok(sht31::Reading {
             temperature,
             humidity,
-        })) => {
-            publish.send_p0(LB::Temperature(temperature));
-            publish.send_p0(LB::Humidity(humidity));
-        }
-        Ok(Err(err)) => {
-            let err = protocol::large_bedroom::SensorError::Sht31(err);
-            let err = protocol::large_bedroom::Error::Running(err);
-            publish.send_error(err)
-        }
-        Err(_timeout) => {
-            let err = protocol::large_bedroom::Error::Timeout(Device::Sht31);
-            publish.send_error(err)
+        }) => {
+            publish_p0(Device::Sht31, LB::Temperature(temperature), publish);
+            publish_p0(Device::Sht31, LB::Humidity(humidity), publish);
         }
+        Err(err) => supervisor.report(err, publish),
     }
 }
 
-fn publish_bme_result<E: fmt::Debug>(
-    bme_res: Result<MeasurementData, bosch_bme680::BmeError<E>>,
+fn publish_bme_result<T>(
+    bme_res: Result<MeasurementData, Error>,
+    supervisor: &Supervisor<'_, T>,
     publish: &Channel,
-) where
-    E: Into<I2cError>,
-{
+) {
     match bme_res {
         Ok(MeasurementData {
             pressure,
@@ -199,13 +224,63 @@ fn publish_bme_result<E: fmt::Debug>(
             ..
         }) => {
             let gas_resistance = unwrap!(gas_resistance); // sensor is on
-            publish.send_p0(LB::GassResistance(gas_resistance));
-            publish.send_p0(LB::Pressure(pressure));
-        }
-        Err(err) => {
-            let err = protocol::large_bedroom::SensorError::Bme680(err.strip_generics());
-            let err = protocol::large_bedroom::Error::Running(err);
-            publish.send_error(err)
+            publish_p0(Device::Bme680, LB::GassResistance(gas_resistance), publish);
+            publish_p0(Device::Bme680, LB::Pressure(pressure), publish);
         }
+        Err(err) => supervisor.report(err, publish),
+    }
+}
+
+/// Keeps the latest value up to date before sending it on
+fn publish_p0(device: Device, value: LB, publish: &Channel) {
+    publish.latest().update(device, &value);
+    publish.send_p0(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+
+    use embassy_futures::block_on;
+    use embassy_futures::select::select3;
+    use protocol::Sensor as Value;
+
+    use super::*;
+    use crate::config::{NodeConfig, Schedule};
+    use crate::sensors::health::{Registry, MAX_CONSECUTIVE_ERRORS};
+    use crate::sensors::mock::{Scripted, Step};
+
+    #[test]
+    fn hanging_sensor_is_reported_and_reinitialized() {
+        let publish = Channel::new();
+        let mut sampling = NodeConfig::default().sampling;
+        sampling.sht31 = Schedule::every(1);
+        let health = Registry::new();
+        let commands = Commands::new(&sampling, &health);
+        let supervisor = Supervisor::new(Device::Sht31, &health);
+        let script = [Step::<sht31::Reading>::Hang];
+        let inits = Cell::new(0);
+
+        let run = supervisor.run(&publish, || {
+            inits.set(inits.get() + 1);
+            async { Ok(Scripted::new(Device::Sht31, &script)) }
+        });
+        let measure = read_sht(&supervisor, &publish, &commands);
+        let reinitialized = async {
+            while inits.get() < 2 {
+                Timer::after_millis(1).await;
+            }
+        };
+        block_on(select3(run, measure, reinitialized));
+
+        // repeats of the same error are only send once
+        let sent = publish.next_ready().map(|sent| sent.value);
+        assert!(matches!(
+            sent,
+            Some(Value::LargeBedroomError(Error::Timeout(Device::Sht31)))
+        ));
+        assert!(publish.next_ready().is_none());
+        let counted = MAX_CONSECUTIVE_ERRORS as u32;
+        assert!(health.error_counts()[..] == [(Device::Sht31, counted)]);
     }
 }
@@ -0,0 +1,36 @@
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::mutex::Mutex;
+use embedded_io_async::{ErrorType, Read, Write};
+
+/// Hands out a uart half behind a mutex, like `shared_bus` does for i2c.
+/// Drivers take ownership of their uart, this lets us create a new driver
+/// from the same uart after a failed init.
+pub struct UartDevice<'a, T> {
+    uart: &'a Mutex<NoopRawMutex, T>,
+}
+
+impl<'a, T> UartDevice<'a, T> {
+    pub fn new(uart: &'a Mutex<NoopRawMutex, T>) -> Self {
+        Self { uart }
+    }
+}
+
+impl<T: ErrorType> ErrorType for UartDevice<'_, T> {
+    type Error = T::Error;
+}
+
+impl<T: Write> Write for UartDevice<'_, T> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.uart.lock().await.write(buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.uart.lock().await.flush().await
+    }
+}
+
+impl<T: Read> Read for UartDevice<'_, T> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.uart.lock().await.read(buf).await
+    }
+}
@@ -0,0 +1,105 @@
+use embassy_net::dns;
+use protocol::large_bedroom::Device;
+use serde::{Deserialize, Serialize};
+
+use crate::sensors::health::Health;
+
+/// Information about the node itself rather than a sensor reading. Send
+/// to the collector in its own frame if it asked for sequenced frames,
+/// see `network::send_published`.
+#[derive(Clone, defmt::Format, Serialize, Deserialize)]
+pub enum Status {
+    SensorHealth {
+        device: Device,
+        health: Health,
+    },
+    Network {
+        /// False if we fell back to the static address
+        dhcp: bool,
+        address: [u8; 4],
+        prefix_len: u8,
+    },
+    /// Could not look up the collector, send once we reach it again
+    ResolveFailed(ResolveError),
+    /// Send right after (re)connecting to the collector
+    Reconnected {
+        attempts: u32,
+        disconnected_secs: u32,
+        /// Messages that did not fit a frame or were cut off when the
+        /// connection broke, those send but not acknowledged might
+        /// have arrived and are not counted
+        lost_messages: u32,
+    },
+    /// The i2c bus got stuck and was recovered
+    I2cBus(BusFault),
+}
+
+/// What was wrong with the i2c bus, see `sensors::bus`
+#[derive(Clone, defmt::Format, Serialize, Deserialize)]
+pub enum BusFault {
+    /// A device held the data line low. Not `released` if it still
+    /// did after clocking out nine bits.
+    SdaStuckLow { released: bool },
+    /// Transactions kept failing while the lines were idle, the
+    /// peripheral was reset
+    TransactionsFailing,
+}
+
+#[derive(Clone, defmt::Format, Serialize, Deserialize)]
+pub enum ResolveError {
+    InvalidName,
+    NameTooLong,
+    Failed,
+}
+
+impl From<dns::Error> for ResolveError {
+    fn from(err: dns::Error) -> Self {
+        match err {
+            dns::Error::InvalidName => Self::InvalidName,
+            dns::Error::NameTooLong => Self::NameTooLong,
+            dns::Error::Failed => Self::Failed,
+        }
+    }
+}
+
+/// Why the node last (re)started
+#[derive(Clone, Copy, defmt::Format, Serialize, Deserialize)]
+pub enum ResetReason {
+    PowerOn,
+    Brownout,
+    /// The reset pin, for example by the debug probe
+    Pin,
+    Software,
+    Watchdog,
+    WindowWatchdog,
+    LowPower,
+}
+
+#[cfg(feature = "board")]
+impl ResetReason {
+    /// Reads the reset flags and clears them so the next reset
+    /// starts with a clean slate. Call once at boot.
+    pub fn read_and_clear() -> Self {
+        use embassy_stm32::pac::RCC;
+
+        let csr = RCC.csr().read();
+        // the flags accumulate, a power on reset also sets the pin flag
+        let reason = if csr.lpwrrstf() {
+            Self::LowPower
+        } else if csr.wwdgrstf() {
+            Self::WindowWatchdog
+        } else if csr.iwdgrstf() {
+            Self::Watchdog
+        } else if csr.sftrstf() {
+            Self::Software
+        } else if csr.porrstf() {
+            Self::PowerOn
+        } else if csr.borrstf() {
+            Self::Brownout
+        } else {
+            Self::Pin
+        };
+        RCC.csr().modify(|w| w.set_rmvf(true));
+        reason
+    }
+}